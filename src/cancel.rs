@@ -0,0 +1,193 @@
+//! Keeps track of running backends so a CancelRequest, which arrives on a connection of its own, reaches the backend it targets.
+//! Reference: https://www.postgresql.org/docs/14/protocol-flow.html#id-1.10.5.7.9
+
+use crate::random_bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Length of the secret key sent in BackendKeyData, which clients speaking protocol 3.0 expect.
+static SECRET_KEY_LENGTH: usize = 4;
+/// Length of the secret key sent in BackendKeyData from protocol 3.2 on, as chosen by PostgreSQL 18.
+pub(crate) static LONG_SECRET_KEY_LENGTH: usize = 32;
+
+/// Secret key of each running backend by process id, together with the signal used to cancel its query.
+type Backends = HashMap<u32, (Vec<u8>, Arc<Notify>)>;
+
+/// Backends of a Postmaster, handing out their process ids.
+#[derive(Debug)]
+pub(crate) struct Registry {
+  next_process_id: AtomicU32,
+  backends: Mutex<Backends>,
+  /// Role each backend logged in as, by process id.
+  sessions: Mutex<HashMap<u32, String>>,
+}
+
+impl Registry {
+  /// Creates an empty registry handing out process ids that follow the one of the Postmaster, as processes forked by it would get.
+  pub(crate) fn new(postmaster_id: u32) -> Arc<Self> {
+    Arc::new(Registry {
+      next_process_id: AtomicU32::new(postmaster_id.wrapping_add(1)),
+      backends: Mutex::new(HashMap::new()),
+      sessions: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Registers a new backend under a process id no other running backend has, together with a random secret key.
+  pub(crate) fn register(self: &Arc<Self>) -> BackendKey {
+    let secret_key = random_bytes(SECRET_KEY_LENGTH);
+    let canceled = Arc::new(Notify::new());
+    let mut backends = self.backends.lock().unwrap();
+    let process_id = loop {
+      let process_id = self.next_process_id.fetch_add(1, Ordering::Relaxed);
+      if process_id != 0 && !backends.contains_key(&process_id) {
+        break process_id;
+      }
+    };
+    let _ = backends.insert(process_id, (secret_key.clone(), canceled.clone()));
+    BackendKey {
+      process_id,
+      secret_key,
+      canceled,
+      registry: self.clone(),
+    }
+  }
+
+  /// Cancels the query the backend is running, if both the process id and the secret key match. Returns whether a backend matched.
+  ///
+  /// Like PostgreSQL, a backend that is not running a query ignores the request.
+  pub(crate) fn cancel(&self, process_id: u32, secret_key: &[u8]) -> bool {
+    match self.backends.lock().unwrap().get(&process_id) {
+      Some((key, canceled)) if key == secret_key => {
+        canceled.notify_waiters();
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Identity of a backend, sent to the client in BackendKeyData so it can later cancel queries. The backend leaves the registry when it
+/// is dropped.
+#[derive(Debug)]
+pub(crate) struct BackendKey {
+  process_id: u32,
+  secret_key: Vec<u8>,
+  canceled: Arc<Notify>,
+  registry: Arc<Registry>,
+}
+
+impl BackendKey {
+  /// Process id reported to the client.
+  pub(crate) fn process_id(&self) -> u32 {
+    self.process_id
+  }
+
+  /// Secret key reported to the client.
+  pub(crate) fn secret_key(&self) -> &[u8] {
+    &self.secret_key
+  }
+
+  /// Replaces the secret key with a random one of a different length, once the protocol version of the session is known.
+  pub(crate) fn renew_secret_key(&mut self, length: usize) {
+    self.secret_key = random_bytes(length);
+    if let Some((key, _)) = self.registry.backends.lock().unwrap().get_mut(&self.process_id) {
+      key.clone_from(&self.secret_key);
+    }
+  }
+
+  /// Registry the backend belongs to, used to cancel the queries of other backends.
+  pub(crate) fn registry(&self) -> &Registry {
+    &self.registry
+  }
+
+  /// Records that the backend logged in as a role, unless `limit` backends already did. Returns whether the backend was let in.
+  /// * `role` - Name of the role.
+  /// * `limit` - Backends allowed to be logged in as the role at the same time, if limited.
+  pub(crate) fn log_in(&self, role: &str, limit: Option<u32>) -> bool {
+    let mut sessions = self.registry.sessions.lock().unwrap();
+    let logged_in = sessions.values().filter(|name| *name == role).count();
+    if limit.is_some_and(|limit| logged_in >= limit as usize) {
+      return false;
+    }
+    let _ = sessions.insert(self.process_id, role.to_owned());
+    true
+  }
+
+  /// Completes once a CancelRequest for this backend arrives after this was called.
+  pub(crate) fn canceled(&self) -> Notified<'_> {
+    self.canceled.notified()
+  }
+}
+
+impl Drop for BackendKey {
+  fn drop(&mut self) {
+    let _ = self.registry.backends.lock().unwrap().remove(&self.process_id);
+    let _ = self.registry.sessions.lock().unwrap().remove(&self.process_id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::Registry;
+  use std::time::Duration;
+
+  #[test]
+  fn test_register() {
+    let registry = Registry::new(u32::MAX - 1);
+    let first = registry.register();
+    let second = registry.register();
+    assert_eq!(first.process_id(), u32::MAX);
+    // 0 is skipped when the ids wrap around.
+    assert_eq!(second.process_id(), 1);
+    assert_eq!(first.secret_key().len(), 4);
+
+    // Backends leave the registry when they stop.
+    drop(first);
+    assert!(!registry.cancel(u32::MAX, &[0; 4]));
+  }
+
+  #[tokio::test]
+  async fn test_cancel() {
+    let registry = Registry::new(100);
+    let key = registry.register();
+    let secret_key = key.secret_key().to_vec();
+
+    // A wrong key or process id is ignored.
+    let mut wrong_key = secret_key.clone();
+    wrong_key[0] = wrong_key[0].wrapping_add(1);
+    assert!(!registry.cancel(key.process_id(), &wrong_key));
+    assert!(!registry.cancel(key.process_id() + 1, &secret_key));
+
+    let canceled = key.canceled();
+    assert!(registry.cancel(key.process_id(), &secret_key));
+    assert!(tokio::time::timeout(Duration::from_secs(1), canceled).await.is_ok());
+
+    // Only the renewed key is accepted.
+    let mut key = key;
+    key.renew_secret_key(32);
+    assert_eq!(key.secret_key().len(), 32);
+    assert!(!registry.cancel(key.process_id(), &secret_key));
+    assert!(registry.cancel(key.process_id(), key.secret_key()));
+  }
+
+  #[test]
+  fn test_log_in() {
+    let registry = Registry::new(100);
+    let first = registry.register();
+    let second = registry.register();
+    let third = registry.register();
+    assert!(first.log_in("alice", Some(1)));
+    assert!(!second.log_in("alice", Some(1)));
+    assert!(second.log_in("bob", Some(1)));
+    assert!(third.log_in("alice", None));
+
+    // Backends leaving make room for others.
+    drop(first);
+    drop(third);
+    assert!(registry.register().log_in("alice", Some(1)));
+  }
+}
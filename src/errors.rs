@@ -1,13 +1,62 @@
 //! Errors specific to the application.
 
 use std::fmt::Display;
+use std::io;
 
 /// Application specific errors.
 #[derive(Debug)]
 pub(crate) enum Errors {
-  ProtocolViolation { message: String },
-  InvalidAuthorizationSpecification { message: String },
-  InvalidPassword { user: String }
+  ProtocolViolation {
+    message: String,
+  },
+  InvalidAuthorizationSpecification {
+    message: String,
+  },
+  InvalidPassword {
+    user: String,
+  },
+  FeatureNotSupported {
+    message: String,
+  },
+  TooManyConnections {
+    message: String,
+  },
+  /// Refusal decided by a custom authenticator, with the SQLSTATE it chose.
+  AuthenticationFailed {
+    code: String,
+    message: String,
+  },
+  QueryCanceled,
+  InFailedSqlTransaction,
+  InvalidSqlStatementName {
+    name: String,
+  },
+  InvalidCursorName {
+    name: String,
+  },
+  Io {
+    source: io::Error,
+  },
+}
+
+impl Errors {
+  /// SQLSTATE reported to the client.
+  /// Reference: https://www.postgresql.org/docs/14/errcodes-appendix.html
+  pub(crate) fn code(&self) -> &str {
+    match self {
+      Errors::ProtocolViolation { .. } => "08P01",
+      Errors::InvalidAuthorizationSpecification { .. } => "28000",
+      Errors::InvalidPassword { .. } => "28P01",
+      Errors::FeatureNotSupported { .. } => "0A000",
+      Errors::TooManyConnections { .. } => "53300",
+      Errors::AuthenticationFailed { code, .. } => code,
+      Errors::QueryCanceled => "57014",
+      Errors::InFailedSqlTransaction => "25P02",
+      Errors::InvalidSqlStatementName { .. } => "26000",
+      Errors::InvalidCursorName { .. } => "34000",
+      Errors::Io { .. } => "08006",
+    }
+  }
 }
 
 impl Display for Errors {
@@ -20,8 +69,47 @@ impl Display for Errors {
         write!(f, "{}", message)
       }
       Errors::InvalidPassword { user } => {
-        write!(f, "password authentication failed for user {}", user)
+        write!(f, "password authentication failed for user \"{}\"", user)
+      }
+      Errors::FeatureNotSupported { message } => {
+        write!(f, "{}", message)
+      }
+      Errors::TooManyConnections { message } => {
+        write!(f, "{}", message)
+      }
+      Errors::AuthenticationFailed { message, .. } => {
+        write!(f, "{}", message)
+      }
+      Errors::QueryCanceled => {
+        write!(f, "canceling statement due to user request")
       }
+      Errors::InFailedSqlTransaction => {
+        write!(f, "current transaction is aborted, commands ignored until end of transaction block")
+      }
+      Errors::InvalidSqlStatementName { name } => {
+        write!(f, "prepared statement \"{}\" does not exist", name)
+      }
+      Errors::InvalidCursorName { name } => {
+        write!(f, "portal \"{}\" does not exist", name)
+      }
+      Errors::Io { source } => {
+        write!(f, "could not receive data from client: {}", source)
+      }
+    }
+  }
+}
+
+impl std::error::Error for Errors {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Errors::Io { source } => Some(source),
+      _ => None,
     }
   }
 }
+
+impl From<io::Error> for Errors {
+  fn from(source: io::Error) -> Self {
+    Errors::Io { source }
+  }
+}
@@ -0,0 +1,140 @@
+//! Handles returned to the caller once the server is listening for clients.
+
+use crate::GenericError;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Handle to a server running as a task of the caller's runtime.
+///
+/// Dropping the handle stops the server and closes every client connection.
+#[derive(Debug)]
+pub struct ServerHandle {
+  address: SocketAddr,
+  socket_path: Option<PathBuf>,
+  ca_certificate: Option<String>,
+  shutdown: Option<oneshot::Sender<()>>,
+  task: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+  pub(crate) fn new(
+    address: SocketAddr,
+    socket_path: Option<PathBuf>,
+    ca_certificate: Option<String>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+  ) -> Self {
+    ServerHandle {
+      address,
+      socket_path,
+      ca_certificate,
+      shutdown: Some(shutdown),
+      task: Some(task),
+    }
+  }
+
+  /// Socket the server is listening in, with the port picked by the operating system if the configured port was 0.
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  /// Path of the Unix domain socket the server is listening in, if one was configured.
+  pub fn socket_path(&self) -> Option<&Path> {
+    self.socket_path.as_deref()
+  }
+
+  /// PEM encoded certificate of the throwaway CA that issued the server certificate, when the server generated its own.
+  /// Clients that trust it can connect with `sslmode=verify-full`.
+  pub fn ca_certificate(&self) -> Option<&str> {
+    self.ca_certificate.as_deref()
+  }
+
+  /// Stops the server, waiting until it no longer accepts clients.
+  pub async fn shutdown(mut self) -> Result<(), GenericError> {
+    if let Some(shutdown) = self.shutdown.take() {
+      let _ = shutdown.send(());
+    }
+    if let Some(task) = self.task.take() {
+      task.await?;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for ServerHandle {
+  fn drop(&mut self) {
+    if let Some(shutdown) = self.shutdown.take() {
+      let _ = shutdown.send(());
+    }
+  }
+}
+
+/// Handle to a server running in a runtime of its own on a dedicated thread.
+///
+/// Dropping the handle stops the server and waits for the thread to finish.
+#[derive(Debug)]
+pub struct BackgroundServer {
+  address: SocketAddr,
+  socket_path: Option<PathBuf>,
+  ca_certificate: Option<String>,
+  stop: Option<oneshot::Sender<()>>,
+  thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundServer {
+  pub(crate) fn new(
+    address: SocketAddr,
+    socket_path: Option<PathBuf>,
+    ca_certificate: Option<String>,
+    stop: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+  ) -> Self {
+    BackgroundServer {
+      address,
+      socket_path,
+      ca_certificate,
+      stop: Some(stop),
+      thread: Some(thread),
+    }
+  }
+
+  /// Socket the server is listening in, with the port picked by the operating system if the configured port was 0.
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  /// Path of the Unix domain socket the server is listening in, if one was configured.
+  pub fn socket_path(&self) -> Option<&Path> {
+    self.socket_path.as_deref()
+  }
+
+  /// PEM encoded certificate of the throwaway CA that issued the server certificate, when the server generated its own.
+  /// Clients that trust it can connect with `sslmode=verify-full`.
+  pub fn ca_certificate(&self) -> Option<&str> {
+    self.ca_certificate.as_deref()
+  }
+
+  /// Stops the server, blocking until its thread finished.
+  pub fn stop(mut self) -> Result<(), GenericError> {
+    self.join()
+  }
+
+  fn join(&mut self) -> Result<(), GenericError> {
+    if let Some(stop) = self.stop.take() {
+      let _ = stop.send(());
+    }
+    if let Some(thread) = self.thread.take() {
+      thread.join().map_err(|_| "postmaster thread panicked")?;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for BackgroundServer {
+  fn drop(&mut self) {
+    let _ = self.join();
+  }
+}
@@ -0,0 +1,509 @@
+//! Decides how each client authenticates out of rules written in the syntax of `pg_hba.conf`, matching the type of connection, the
+//! database, the user and the address of the client.
+//! Reference: https://www.postgresql.org/docs/14/auth-pg-hba-conf.html
+
+use crate::errors::Errors;
+use crate::{AuthenticationType, Configuration, GenericError};
+use std::net::IpAddr;
+
+/// Kind of connection a rule applies to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ConnectionType {
+  /// Unix domain sockets, and in-memory connections.
+  Local,
+  /// TCP connections, encrypted or not.
+  Host,
+  /// TCP connections encrypted with TLS.
+  HostSsl,
+  /// TCP connections that are not encrypted.
+  HostNoSsl,
+}
+
+/// Word of a rule, which is only a keyword such as `all` when it is not quoted.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Token {
+  pub(crate) value: String,
+  pub(crate) quoted: bool,
+}
+
+impl Token {
+  /// Whether the token is the keyword supplied.
+  pub(crate) fn is_keyword(&self, keyword: &str) -> bool {
+    !self.quoted && self.value == keyword
+  }
+}
+
+/// Addresses a rule for TCP connections applies to.
+#[derive(Debug, PartialEq, Clone)]
+enum Address {
+  All,
+  /// Network made of an address and the length of its prefix.
+  Network(IpAddr, u32),
+}
+
+/// Line of `pg_hba.conf`.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct HbaRule {
+  connection_type: ConnectionType,
+  databases: Vec<Token>,
+  users: Vec<Token>,
+  address: Option<Address>,
+  method: AuthenticationType,
+}
+
+/// What is known about a client when its authentication method is chosen.
+#[derive(Debug)]
+pub(crate) struct Client<'a> {
+  /// Address of the client, missing for connections that do not go through TCP.
+  pub(crate) address: Option<IpAddr>,
+  pub(crate) tls: bool,
+  pub(crate) database: &'a str,
+  pub(crate) user: &'a str,
+}
+
+impl Client<'_> {
+  /// Describes the client the way PostgreSQL does in errors refusing it.
+  fn describe(&self) -> String {
+    let host = self.address.map(|address| address.to_string()).unwrap_or_else(|| "[local]".to_owned());
+    let encryption = if self.tls { "SSL encryption" } else { "no encryption" };
+    format!(
+      "host \"{}\", user \"{}\", database \"{}\", {}",
+      host, self.user, self.database, encryption
+    )
+  }
+}
+
+/// Chooses how a client authenticates, using the first rule that matches it or the configured authentication type when there are no
+/// rules.
+/// * `configuration` - Configuration holding the rules and the roles groups are made of.
+/// * `client` - Client to authenticate.
+pub(crate) fn authentication_method(configuration: &Configuration, client: &Client<'_>) -> Result<AuthenticationType, Errors> {
+  let rules = match &configuration.hba {
+    Some(rules) => rules,
+    None if configuration.authentication_type == AuthenticationType::Reject => {
+      return Err(Errors::InvalidAuthorizationSpecification {
+        message: format!("connection rejected for {}", client.describe()),
+      })
+    }
+    None => return Ok(configuration.authentication_type.clone()),
+  };
+  match rules.iter().find(|rule| rule.matches(configuration, client)) {
+    Some(rule) if rule.method == AuthenticationType::Reject => Err(Errors::InvalidAuthorizationSpecification {
+      message: format!("pg_hba.conf rejects connection for {}", client.describe()),
+    }),
+    Some(rule) => Ok(rule.method.clone()),
+    None => Err(Errors::InvalidAuthorizationSpecification {
+      message: format!("no pg_hba.conf entry for {}", client.describe()),
+    }),
+  }
+}
+
+impl HbaRule {
+  /// Whether the rule applies to a client.
+  fn matches(&self, configuration: &Configuration, client: &Client<'_>) -> bool {
+    let connection_type = match (self.connection_type, client.address) {
+      (ConnectionType::Local, address) => address.is_none(),
+      (ConnectionType::Host, address) => address.is_some(),
+      (ConnectionType::HostSsl, address) => address.is_some() && client.tls,
+      (ConnectionType::HostNoSsl, address) => address.is_some() && !client.tls,
+    };
+    let address = match (&self.address, client.address) {
+      (Some(Address::Network(network, prefix)), Some(address)) => in_network(address, *network, *prefix),
+      _ => true,
+    };
+    let database = self.databases.iter().any(|token| {
+      if token.is_keyword("all") {
+        true
+      } else if token.is_keyword("sameuser") {
+        client.database == client.user
+      } else if token.is_keyword("samerole") || token.is_keyword("samegroup") {
+        is_member(configuration, client.user, client.database)
+      } else if token.is_keyword("replication") {
+        // Replication connections are not supported, so the keyword never matches.
+        false
+      } else {
+        token.value == client.database
+      }
+    });
+    let user = self.users.iter().any(|token| match token.value.strip_prefix('+') {
+      Some(group) if !token.quoted => is_member(configuration, client.user, group),
+      _ => token.is_keyword("all") || token.value == client.user,
+    });
+    connection_type && address && database && user
+  }
+}
+
+/// Whether a role is a group or a member of it, directly or through other groups.
+pub(crate) fn is_member(configuration: &Configuration, user: &str, group: &str) -> bool {
+  let mut pending = vec![user];
+  let mut seen = Vec::new();
+  while let Some(name) = pending.pop() {
+    if name == group {
+      return true;
+    }
+    if seen.contains(&name) {
+      continue;
+    }
+    seen.push(name);
+    if let Some(role) = configuration.role(name) {
+      pending.extend(role.member_of.iter().map(String::as_str));
+    }
+  }
+  false
+}
+
+/// Whether an address belongs to a network. Addresses of another family never do.
+fn in_network(address: IpAddr, network: IpAddr, prefix: u32) -> bool {
+  match (address, network) {
+    (IpAddr::V4(address), IpAddr::V4(network)) => {
+      let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+      u32::from(address) & mask == u32::from(network) & mask
+    }
+    (IpAddr::V6(address), IpAddr::V6(network)) => {
+      let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+      u128::from(address) & mask == u128::from(network) & mask
+    }
+    _ => false,
+  }
+}
+
+/// Parses the contents of a `pg_hba.conf` file, refusing it as a whole if any line is invalid, as PostgreSQL does on reload.
+/// * `contents` - Rules, one per line, with `#` starting comments and `\` at the end of a line continuing it on the next.
+pub(crate) fn parse(contents: &str) -> Result<Vec<HbaRule>, GenericError> {
+  parse_records(contents, "pg_hba.conf", rule)
+}
+
+/// Parses each record of a configuration file written with the syntax of `pg_hba.conf`, which `pg_ident.conf` shares.
+/// * `contents` - Records, one per line, with `#` starting comments and `\` at the end of a line continuing it on the next.
+/// * `file` - Name of the file, mentioned in errors along with the line.
+/// * `record` - Builds a record out of the fields of a line.
+pub(crate) fn parse_records<T>(contents: &str, file: &str, record: fn(Vec<Vec<Token>>) -> Result<T, String>) -> Result<Vec<T>, GenericError> {
+  let mut records = Vec::new();
+  let mut pending = String::new();
+  let mut first_line = 1;
+  for (index, line) in contents.lines().enumerate() {
+    if pending.is_empty() {
+      first_line = index + 1;
+    }
+    match line.strip_suffix('\\') {
+      Some(line) => {
+        pending.push_str(line);
+        pending.push(' ');
+        continue;
+      }
+      None => pending.push_str(line),
+    }
+    let fields = tokenize(&pending).map_err(|error| format!("{} on line {} of {}", error, first_line, file))?;
+    pending.clear();
+    if !fields.is_empty() {
+      records.push(record(fields).map_err(|error| format!("{} on line {} of {}", error, first_line, file))?);
+    }
+  }
+  Ok(records)
+}
+
+/// Splits a line into fields made of comma separated tokens, dropping comments.
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Vec<Token>>, String> {
+  let mut fields: Vec<Vec<Token>> = Vec::new();
+  let mut chars = line.chars().peekable();
+  // Whether the previous token ended with a comma, in which case the next one belongs to the same field.
+  let mut continued = false;
+  loop {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    match chars.peek() {
+      None | Some('#') => break,
+      _ => {}
+    }
+    let mut token = Token {
+      value: String::new(),
+      quoted: false,
+    };
+    let mut in_quotes = false;
+    let mut comma = false;
+    while let Some(c) = chars.peek().copied() {
+      if !in_quotes && (c.is_whitespace() || c == '#') {
+        break;
+      }
+      let _ = chars.next();
+      match c {
+        '"' => {
+          in_quotes = !in_quotes;
+          token.quoted = true;
+        }
+        ',' if !in_quotes => {
+          comma = true;
+          break;
+        }
+        c => token.value.push(c),
+      }
+    }
+    if in_quotes {
+      return Err("unterminated quoted string".to_owned());
+    }
+    match fields.last_mut() {
+      Some(field) if continued => field.push(token),
+      _ => fields.push(vec![token]),
+    }
+    continued = comma;
+  }
+  Ok(fields)
+}
+
+/// Builds a rule out of the fields of a line.
+fn rule(fields: Vec<Vec<Token>>) -> Result<HbaRule, String> {
+  let mut fields = fields.into_iter();
+  let connection_type = match single(fields.next(), "connection type")?.value.as_str() {
+    "local" => ConnectionType::Local,
+    "host" => ConnectionType::Host,
+    "hostssl" => ConnectionType::HostSsl,
+    "hostnossl" => ConnectionType::HostNoSsl,
+    other => return Err(format!("invalid connection type \"{}\"", other)),
+  };
+  let databases = list(fields.next(), "database")?;
+  let users = list(fields.next(), "role")?;
+  let address = match connection_type {
+    ConnectionType::Local => None,
+    _ => {
+      let address = single(fields.next(), "IP address")?;
+      if address.is_keyword("all") {
+        Some(Address::All)
+      } else if let Some((ip, prefix)) = address.value.split_once('/') {
+        let ip: IpAddr = ip.parse().map_err(|_| format!("invalid IP address \"{}\"", ip))?;
+        let maximum = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+          .parse()
+          .ok()
+          .filter(|prefix| *prefix <= maximum)
+          .ok_or_else(|| format!("invalid CIDR mask in address \"{}\"", address.value))?;
+        Some(Address::Network(ip, prefix))
+      } else if let Ok(ip) = address.value.parse::<IpAddr>() {
+        let mask = single(fields.next(), "netmask")?;
+        Some(Address::Network(ip, prefix_length(ip, &mask.value)?))
+      } else {
+        return Err(format!(
+          "host name or keyword \"{}\" is not supported, use an IP address with a mask",
+          address.value
+        ));
+      }
+    }
+  };
+  let mut method = match single(fields.next(), "authentication method")?.value.as_str() {
+    "trust" => AuthenticationType::Trust,
+    "reject" => AuthenticationType::Reject,
+    "password" => AuthenticationType::AuthenticationCleartextPassword,
+    "md5" => AuthenticationType::AuthenticationMD5Password,
+    "scram-sha-256" => AuthenticationType::AuthenticationSASL,
+    "cert" if connection_type == ConnectionType::HostSsl => AuthenticationType::Certificate { map: None },
+    "cert" => return Err("cert authentication is only supported on hostssl connections".to_owned()),
+    other => return Err(format!("invalid authentication method \"{}\"", other)),
+  };
+  // Options other than the user name map are accepted but do not change how the mock authenticates.
+  for option in fields.flatten() {
+    match (option.value.split_once('='), &mut method) {
+      (Some(("map", name)), AuthenticationType::Certificate { map }) => *map = Some(name.to_owned()),
+      (Some(("map", _)), _) => return Err("authentication option \"map\" is only valid for authentication method cert".to_owned()),
+      (Some(_), _) => {}
+      (None, _) => return Err(format!("authentication option not in name=value format: {}", option.value)),
+    }
+  }
+  Ok(HbaRule {
+    connection_type,
+    databases,
+    users,
+    address,
+    method,
+  })
+}
+
+/// Takes a field holding a single token.
+fn single(field: Option<Vec<Token>>, name: &str) -> Result<Token, String> {
+  match field {
+    Some(mut tokens) if tokens.len() == 1 => Ok(tokens.remove(0)),
+    Some(_) => Err(format!("multiple values specified for {}", name)),
+    None => Err(format!("end-of-line before {} specification", name)),
+  }
+}
+
+/// Takes a field holding a list of databases or roles.
+fn list(field: Option<Vec<Token>>, name: &str) -> Result<Vec<Token>, String> {
+  let tokens = field.ok_or_else(|| format!("end-of-line before {} specification", name))?;
+  match tokens.iter().find(|token| !token.quoted && token.value.starts_with('@')) {
+    Some(token) => Err(format!(
+      "could not open secondary authentication file \"{}\": file inclusion is not supported",
+      token.value
+    )),
+    None => Ok(tokens),
+  }
+}
+
+/// Converts a mask such as `255.255.255.0` into the length of its prefix.
+fn prefix_length(ip: IpAddr, mask: &str) -> Result<u32, String> {
+  let invalid = || format!("invalid IP mask \"{}\"", mask);
+  let (bits, width) = match (ip, mask.parse::<IpAddr>().map_err(|_| invalid())?) {
+    (IpAddr::V4(_), IpAddr::V4(mask)) => (u32::from(mask) as u128, 32),
+    (IpAddr::V6(_), IpAddr::V6(mask)) => (u128::from(mask), 128),
+    _ => return Err("IP address and mask do not match".to_owned()),
+  };
+  let prefix = (bits << (128 - width)).leading_ones();
+  if bits.count_ones() != prefix {
+    return Err(invalid());
+  }
+  Ok(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{authentication_method, parse, Address, Client, ConnectionType, Token};
+  use crate::errors::Errors;
+  use crate::{AuthenticationType, Configuration, Role};
+  use std::net::IpAddr;
+
+  /// TCP client connecting from 10.0.0.1 without TLS.
+  fn client<'a>(database: &'a str, user: &'a str) -> Client<'a> {
+    Client {
+      address: Some("10.0.0.1".parse().unwrap()),
+      tls: false,
+      database,
+      user,
+    }
+  }
+
+  #[test]
+  fn test_parse() {
+    let rules = parse(
+      r#"
+# TYPE  DATABASE        USER            ADDRESS                 METHOD
+local   all             postgres                                trust
+host    "all",sameuser  +admins         192.168.0.0/16          scram-sha-256
+hostssl db1, db2        alice           10.0.0.0 255.0.0.0      cert clientcert=verify-full map=users # comment
+hostnossl all           all             ::1/128                 md5
+host    all             all             all                     \
+                                                                reject
+"#,
+    )
+    .unwrap();
+    assert_eq!(rules.len(), 5);
+    assert_eq!(rules[0].connection_type, ConnectionType::Local);
+    assert_eq!(rules[0].address, None);
+    assert_eq!(
+      rules[1].databases,
+      vec![
+        Token {
+          value: "all".to_owned(),
+          quoted: true
+        },
+        Token {
+          value: "sameuser".to_owned(),
+          quoted: false
+        }
+      ]
+    );
+    assert_eq!(rules[1].address, Some(Address::Network("192.168.0.0".parse().unwrap(), 16)));
+    assert_eq!(rules[1].method, AuthenticationType::AuthenticationSASL);
+    assert_eq!(rules[2].databases.len(), 2);
+    assert_eq!(rules[2].address, Some(Address::Network("10.0.0.0".parse().unwrap(), 8)));
+    assert_eq!(
+      rules[2].method,
+      AuthenticationType::Certificate {
+        map: Some("users".to_owned())
+      }
+    );
+    assert_eq!(rules[3].address, Some(Address::Network("::1".parse().unwrap(), 128)));
+    assert_eq!(rules[4].address, Some(Address::All));
+    assert_eq!(rules[4].method, AuthenticationType::Reject);
+
+    for (line, error) in [
+      ("hostgssenc all all all trust", "invalid connection type \"hostgssenc\""),
+      ("host all all", "end-of-line before IP address specification"),
+      ("host all all 10.0.0.0/33 trust", "invalid CIDR mask in address \"10.0.0.0/33\""),
+      ("host all all 10.0.0.0 255.0.255.0 trust", "invalid IP mask \"255.0.255.0\""),
+      (
+        "host all all example.com trust",
+        "host name or keyword \"example.com\" is not supported, use an IP address with a mask",
+      ),
+      ("local all all ident", "invalid authentication method \"ident\""),
+      ("host all all all cert", "cert authentication is only supported on hostssl connections"),
+      ("local all all trust map", "authentication option not in name=value format: map"),
+      (
+        "local all all trust map=users",
+        "authentication option \"map\" is only valid for authentication method cert",
+      ),
+      ("local \"all all trust", "unterminated quoted string"),
+    ] {
+      assert_eq!(
+        parse(&format!("\n{}", line)).unwrap_err().to_string(),
+        format!("{} on line 2 of pg_hba.conf", error)
+      );
+    }
+  }
+
+  #[test]
+  fn test_authentication_method() {
+    let configuration = Configuration::default()
+      .with_role(Role::new("alice".to_owned()).with_member_of("admins".to_owned()))
+      .with_role(Role::new("admins".to_owned()).with_login(false))
+      .with_hba(
+        r#"
+host    all       +admins   10.0.0.0/8      md5
+host    sameuser  all       10.0.0.0/8      password
+hostssl all       all       10.0.0.0/8      scram-sha-256
+local   all       all                       trust
+host    all       bob       all             reject
+"#,
+      )
+      .unwrap();
+    let method = |client: Client<'_>| authentication_method(&configuration, &client);
+
+    assert_eq!(method(client("db", "alice")).unwrap(), AuthenticationType::AuthenticationMD5Password);
+    assert_eq!(method(client("bob", "bob")).unwrap(), AuthenticationType::AuthenticationCleartextPassword);
+    let tls = Client {
+      tls: true,
+      ..client("db", "carol")
+    };
+    assert_eq!(method(tls).unwrap(), AuthenticationType::AuthenticationSASL);
+    let local = Client {
+      address: None,
+      ..client("db", "carol")
+    };
+    assert_eq!(method(local).unwrap(), AuthenticationType::Trust);
+
+    match method(client("db", "bob")) {
+      Err(Errors::InvalidAuthorizationSpecification { message }) => {
+        assert_eq!(
+          message,
+          "pg_hba.conf rejects connection for host \"10.0.0.1\", user \"bob\", database \"db\", no encryption"
+        )
+      }
+      result => panic!("Unexpected result {:?}", result),
+    }
+    let other_network = Client {
+      address: Some(IpAddr::from([192, 168, 0, 1])),
+      ..client("db", "carol")
+    };
+    match method(other_network) {
+      Err(Errors::InvalidAuthorizationSpecification { message }) => assert_eq!(
+        message,
+        "no pg_hba.conf entry for host \"192.168.0.1\", user \"carol\", database \"db\", no encryption"
+      ),
+      result => panic!("Unexpected result {:?}", result),
+    }
+
+    // Without rules the configured authentication type applies to everyone.
+    assert_eq!(
+      authentication_method(&Configuration::default(), &client("db", "carol")).unwrap(),
+      AuthenticationType::Trust
+    );
+    let reject = Configuration {
+      authentication_type: AuthenticationType::Reject,
+      ..Configuration::default()
+    };
+    match authentication_method(&reject, &client("db", "carol")) {
+      Err(Errors::InvalidAuthorizationSpecification { message }) => assert_eq!(
+        message,
+        "connection rejected for host \"10.0.0.1\", user \"carol\", database \"db\", no encryption"
+      ),
+      result => panic!("Unexpected result {:?}", result),
+    }
+  }
+}
@@ -0,0 +1,142 @@
+//! Maps the names clients are known by outside the database, such as the common name of a TLS certificate, to the roles they may
+//! connect as, out of entries written in the syntax of `pg_ident.conf`.
+//! Reference: https://www.postgresql.org/docs/14/auth-username-maps.html
+
+use crate::hba::{is_member, parse_records, Token};
+use crate::{Configuration, GenericError};
+use regex::Regex;
+
+/// Name a client is known by outside the database.
+#[derive(Debug, Clone)]
+enum SystemUser {
+  /// Name matched exactly.
+  Name(String),
+  /// Regular expression, written after a `/`, whose first capture group can be substituted for `\1` in the role.
+  Regex(Regex),
+}
+
+/// Line of `pg_ident.conf`.
+#[derive(Debug, Clone)]
+pub(crate) struct IdentEntry {
+  map: String,
+  system_user: SystemUser,
+  database_user: Token,
+}
+
+/// Parses entries written as in `pg_ident.conf`, one per line made of the name of the map, the system user and the role.
+/// * `contents` - Contents of a `pg_ident.conf` file.
+pub(crate) fn parse(contents: &str) -> Result<Vec<IdentEntry>, GenericError> {
+  parse_records(contents, "pg_ident.conf", entry)
+}
+
+/// Builds an entry out of the fields of a line.
+fn entry(fields: Vec<Vec<Token>>) -> Result<IdentEntry, String> {
+  if fields.iter().any(|field| field.len() != 1) {
+    return Err("multiple values in ident field".to_owned());
+  }
+  let mut tokens = fields.into_iter().flatten();
+  let (map, system_user, database_user) = match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+    (Some(map), Some(system_user), Some(database_user), None) => (map, system_user, database_user),
+    (_, _, _, Some(_)) => return Err("extra fields in ident entry".to_owned()),
+    _ => return Err("missing entry at end of line".to_owned()),
+  };
+  let system_user = match system_user.value.strip_prefix('/') {
+    Some(pattern) => SystemUser::Regex(Regex::new(pattern).map_err(|error| format!("invalid regular expression \"{}\": {}", pattern, error))?),
+    None => SystemUser::Name(system_user.value),
+  };
+  Ok(IdentEntry {
+    map: map.value,
+    system_user,
+    database_user,
+  })
+}
+
+/// Whether a map allows a client known by a system user name to connect as a role.
+/// * `configuration` - Configuration holding the entries and the roles groups are made of.
+/// * `map` - Name of the map, where entries with another name are ignored.
+/// * `user` - Role the client asked for.
+/// * `system_user` - Name the client is known by outside the database.
+pub(crate) fn check(configuration: &Configuration, map: &str, user: &str, system_user: &str) -> bool {
+  configuration.ident.iter().filter(|entry| entry.map == map).any(|entry| {
+    let database_user = match &entry.system_user {
+      SystemUser::Name(name) if name == system_user => entry.database_user.value.clone(),
+      SystemUser::Name(_) => return false,
+      SystemUser::Regex(regex) => match regex.captures(system_user) {
+        Some(captures) => match captures.get(1) {
+          Some(capture) => entry.database_user.value.replacen("\\1", capture.as_str(), 1),
+          None => entry.database_user.value.clone(),
+        },
+        None => return false,
+      },
+    };
+    if entry.database_user.is_keyword("all") {
+      return true;
+    }
+    match database_user.strip_prefix('+') {
+      Some(group) if !entry.database_user.quoted => is_member(configuration, user, group),
+      _ => database_user == user,
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{check, parse};
+  use crate::{Configuration, Role};
+
+  #[test]
+  fn test_parse() {
+    let entries = parse(
+      "# MAPNAME  SYSTEM-USERNAME  PG-USERNAME
+users       alice            alice
+users       /^(.*)@example\\.com$  \\1
+
+admins      \"root\"         postgres # comment
+",
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].map, "users");
+    assert_eq!(entries[1].database_user.value, "\\1");
+    assert_eq!(entries[2].database_user.value, "postgres");
+
+    let errors = [
+      ("users alice", "missing entry at end of line on line 1 of pg_ident.conf"),
+      ("users alice alice bob", "extra fields in ident entry on line 1 of pg_ident.conf"),
+      ("users alice,bob alice", "multiple values in ident field on line 1 of pg_ident.conf"),
+      ("\n\nusers /( alice", "invalid regular expression \"(\""),
+      ("users \"alice alice", "unterminated quoted string on line 1 of pg_ident.conf"),
+    ];
+    for (contents, error) in errors {
+      let message = parse(contents).unwrap_err().to_string();
+      assert!(message.starts_with(error), "{}", message);
+    }
+  }
+
+  #[test]
+  fn test_check() {
+    let configuration = Configuration::default()
+      .with_role(Role::new("alice".to_owned()))
+      .with_role(Role::new("bob".to_owned()).with_member_of("staff".to_owned()))
+      .with_ident(
+        "users   alice                 alice
+users   /^(.*)@example\\.com$  \\1
+staff   /@staff$              +staff
+anyone  root                  all
+quoted  /x                    \"+staff\"
+",
+      )
+      .unwrap();
+    assert!(check(&configuration, "users", "alice", "alice"));
+    assert!(!check(&configuration, "users", "bob", "alice"));
+    assert!(check(&configuration, "users", "bob", "bob@example.com"));
+    assert!(!check(&configuration, "users", "bob", "bob@example.org"));
+    assert!(!check(&configuration, "other", "alice", "alice"));
+    assert!(check(&configuration, "staff", "bob", "anyone@staff"));
+    assert!(!check(&configuration, "staff", "alice", "anyone@staff"));
+    assert!(check(&configuration, "anyone", "postgres", "root"));
+    assert!(!check(&configuration, "anyone", "postgres", "alice"));
+    assert!(!check(&configuration, "quoted", "bob", "x"));
+  }
+}
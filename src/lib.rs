@@ -16,16 +16,47 @@
 #![deny(trivial_numeric_casts)]
 #![deny(absolute_paths_not_starting_with_crate)]
 
+mod cancel;
 mod errors;
+mod handle;
+mod hba;
+mod ident;
+mod listener;
+mod role;
 mod stream;
+mod tls;
 mod v3;
 
+pub use handle::{BackgroundServer, ServerHandle};
+pub use role::Role;
+pub use v3::{AuthenticationClient, AuthenticationError, AuthenticationFuture, AuthenticationRequest, Authenticator};
+
+use cancel::Registry;
+use hba::HbaRule;
+use ident::IdentEntry;
+use listener::Listener;
 use std::error::Error;
+use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process;
-use tokio::net::TcpListener;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use stream::Stream;
+use tls::Tls;
+use tokio::io::{self, DuplexStream};
+use tokio::runtime;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
 use v3::Backend;
 
+/// Bytes an in-memory connection holds in each direction before writes wait for the other side to read.
+static DUPLEX_BUFFER_LENGTH: usize = 64 * 1024;
+/// Time the server waits before accepting clients again when accepting one failed.
+static ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 /// Error returned by the library.
 pub type GenericError = Box<dyn Error + Send + Sync + 'static>;
 
@@ -40,6 +71,106 @@ pub enum AuthenticationType {
   AuthenticationMD5Password,
   /// Server performs a SASL handshake
   AuthenticationSASL,
+  /// Server refuses the connection
+  Reject,
+  /// Server walks the client through a GSSAPI negotiation scripted byte for byte, since there is no KDC to check tokens against
+  AuthenticationGSS {
+    /// Each token the client must send in a GSSResponse, in order, with the token the server answers it with in
+    /// AuthenticationGSSContinue. Only the last answer may be left empty to skip it, since the client waits for one before sending its next
+    /// token. AuthenticationOk follows the last one, or any first token when there is none.
+    tokens: Vec<(Vec<u8>, Vec<u8>)>,
+  },
+  /// Server requires a TLS client certificate whose common name is the user, or is mapped to the user by the `pg_ident.conf` map named
+  Certificate {
+    /// Name of the map translating common names into roles.
+    map: Option<String>,
+  },
+}
+
+impl AuthenticationType {
+  /// Name of the method in `pg_hba.conf`, which errors describe it with.
+  pub(crate) fn name(&self) -> &'static str {
+    match self {
+      AuthenticationType::Trust => "trust",
+      AuthenticationType::AuthenticationCleartextPassword => "password",
+      AuthenticationType::AuthenticationMD5Password => "md5",
+      AuthenticationType::AuthenticationSASL => "scram-sha-256",
+      AuthenticationType::Reject => "reject",
+      AuthenticationType::AuthenticationGSS { .. } => "gss",
+      AuthenticationType::Certificate { .. } => "cert",
+    }
+  }
+}
+
+/// Version of the wire protocol, made of a major and a minor number.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct ProtocolVersion {
+  major: u16,
+  minor: u16,
+}
+
+impl ProtocolVersion {
+  /// Version 2.0, dropped by PostgreSQL 14.
+  pub const V2_0: ProtocolVersion = ProtocolVersion { major: 2, minor: 0 };
+  /// Version 3.0, spoken by every PostgreSQL release since 7.4.
+  pub const V3_0: ProtocolVersion = ProtocolVersion { major: 3, minor: 0 };
+  /// Version 3.2, introduced by PostgreSQL 18 with longer cancel keys.
+  pub const V3_2: ProtocolVersion = ProtocolVersion { major: 3, minor: 2 };
+
+  /// Creates a protocol version out of its major and minor numbers.
+  pub fn new(major: u16, minor: u16) -> Self {
+    ProtocolVersion { major, minor }
+  }
+
+  /// Major version number.
+  pub fn major(self) -> u16 {
+    self.major
+  }
+
+  /// Minor version number.
+  pub fn minor(self) -> u16 {
+    self.minor
+  }
+}
+
+impl From<u32> for ProtocolVersion {
+  /// Splits the code sent by clients, holding the major version in the most significant 16 bits and the minor in the least.
+  fn from(code: u32) -> Self {
+    ProtocolVersion::new((code >> 16) as u16, code as u16)
+  }
+}
+
+impl Display for ProtocolVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}", self.major, self.minor)
+  }
+}
+
+/// How the server answers GSSENCRequest, which clients send first when they would like the connection encrypted with GSSAPI, for
+/// example libpq with `gssencmode=prefer` on machines with Kerberos credentials.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GssEncryption {
+  /// Answers 'N' and waits for the next packet on the same connection, as a server built without GSSAPI does.
+  Refuse,
+  /// Closes the connection without answering.
+  Drop,
+  /// Answers 'G' and then closes the connection since the mock can't establish a GSSAPI security context, leaving the client halfway
+  /// through the handshake.
+  Accept,
+}
+
+/// How the server handles SCRAM-SHA-256-PLUS, the SASL mechanism that binds the exchange to the TLS connection so a man in the middle
+/// can't relay it. Only the default is correct, the others help check that clients refuse downgrade attacks.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChannelBinding {
+  /// Offers SCRAM-SHA-256-PLUS over TLS and checks the `tls-server-end-point` binding data, as PostgreSQL does.
+  Offer,
+  /// Offers only SCRAM-SHA-256 even over TLS, as a man in the middle stripping channel binding would. Clients with
+  /// `channel_binding=require` must give up.
+  Omit,
+  /// Offers SCRAM-SHA-256-PLUS over TLS and accepts the binding data of the client, but signs the server-final-message as if it had
+  /// received other data, as a man in the middle would. Clients must refuse the server when they check its signature.
+  Wrong,
 }
 
 /// Configuration data used to define the behaviour of the server.
@@ -50,23 +181,213 @@ pub struct Configuration {
   dbname: Option<String>,
   hostaddr: SocketAddr,
   authentication_type: AuthenticationType,
+  socket_directory: Option<PathBuf>,
+  socket_port: u16,
+  tls: Option<Tls>,
+  client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+  direct_tls: bool,
+  gss_encryption: GssEncryption,
+  minimum_protocol_version: ProtocolVersion,
+  maximum_protocol_version: ProtocolVersion,
+  protocol_options: Vec<String>,
+  legacy_protocol: bool,
+  scram_salt: Option<Vec<u8>>,
+  scram_iterations: Option<u32>,
+  channel_binding: ChannelBinding,
+  roles: Vec<Role>,
+  hba: Option<Vec<HbaRule>>,
+  ident: Vec<IdentEntry>,
+  authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 impl Configuration {
   /// Creates a configuration object with user supplied values.
-  /// * `user` - Database username.
-  /// * `password` - Database password.
+  /// * `user` - Database username, the name of a superuser role clients can connect as.
+  /// * `password` - Password of the superuser role, in clear text or hashed as `pg_authid` stores it, either `md5` followed by 32
+  ///   hexadecimal digits or a SCRAM-SHA-256 verifier written `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>`.
   /// * `dbname` - Database the client will connect to.
   /// * `hostadd` - Socket the backend will listen in.
   /// * `authentication_type` - The type of authentication the backend will perform.
   pub fn new(user: String, password: Option<String>, dbname: Option<String>, hostaddr: SocketAddr, authentication_type: AuthenticationType) -> Self {
+    let roles = vec![superuser(&user, password.clone())];
     Configuration {
       user,
       password,
       dbname,
       hostaddr,
       authentication_type,
+      socket_directory: None,
+      socket_port: 0,
+      tls: None,
+      client_verifier: None,
+      direct_tls: true,
+      gss_encryption: GssEncryption::Refuse,
+      minimum_protocol_version: ProtocolVersion::V3_0,
+      maximum_protocol_version: ProtocolVersion::V3_0,
+      protocol_options: Vec::new(),
+      legacy_protocol: false,
+      scram_salt: None,
+      scram_iterations: None,
+      channel_binding: ChannelBinding::Offer,
+      roles,
+      hba: None,
+      ident: Vec::new(),
+      authenticator: None,
+    }
+  }
+
+  /// Also listens in a Unix domain socket named `.s.PGSQL.<port>` inside `directory`, as PostgreSQL does, so clients can connect with
+  /// `host=<directory>`. A `port` of 0 names the socket after the port of the TCP socket.
+  /// * `directory` - Directory the socket is created in, which must exist.
+  /// * `port` - Port used to name the socket.
+  pub fn with_unix_socket(mut self, directory: PathBuf, port: u16) -> Self {
+    self.socket_directory = Some(directory);
+    self.socket_port = port;
+    self
+  }
+
+  /// Answers SSLRequest with 'S' and encrypts the connection using the certificate chain and private key supplied, both PEM encoded.
+  /// Without it the server answers 'N' and clients that require TLS give up.
+  /// * `certificate_chain` - Server certificate followed by any intermediate certificates.
+  /// * `private_key` - Key of the server certificate.
+  pub fn with_tls(mut self, certificate_chain: &[u8], private_key: &[u8]) -> Result<Self, GenericError> {
+    self.tls = Some(self.verify_clients(Tls::new(certificate_chain, private_key)?)?);
+    Ok(self)
+  }
+
+  /// Answers SSLRequest with 'S' using a certificate generated on the spot, issued by a throwaway CA for `localhost` and the IP address
+  /// in `hostaddr`. The CA certificate can be read from the server handle so clients can trust it, even with `sslmode=verify-full`.
+  pub fn with_generated_tls(mut self) -> Result<Self, GenericError> {
+    self.tls = Some(self.verify_clients(Tls::generate(&self.hostaddr)?)?);
+    Ok(self)
+  }
+
+  /// Whether clients may skip SSLRequest and start the TLS handshake right away, as PostgreSQL 17 clients do with
+  /// `sslnegotiation=direct`. Allowed by default, it only has an effect when TLS is configured. When forbidden the connection is closed
+  /// as soon as the handshake starts, so clients have to fall back to SSLRequest.
+  /// * `allowed` - Whether direct TLS is accepted.
+  pub fn with_direct_tls(mut self, allowed: bool) -> Self {
+    self.direct_tls = allowed;
+    self
+  }
+
+  /// Changes how GSSENCRequest is answered, which by default is refused with 'N'.
+  /// * `gss_encryption` - Answer given to clients asking for GSSAPI encryption.
+  pub fn with_gss_encryption(mut self, gss_encryption: GssEncryption) -> Self {
+    self.gss_encryption = gss_encryption;
+    self
+  }
+
+  /// Changes the range of protocol versions accepted, which is 3.0 to 3.0 by default.
+  ///
+  /// Clients asking for a newer minor version than the maximum are told through NegotiateProtocolVersion that the maximum will be used
+  /// instead, as the protocol specifies. Clients asking for a version below the minimum, or for another major version, are refused. With
+  /// 3.2 backends send 32 byte secret keys in BackendKeyData, as PostgreSQL 18 does, instead of 4 byte ones.
+  /// * `minimum` - Oldest version accepted.
+  /// * `maximum` - Newest version accepted.
+  pub fn with_protocol_versions(mut self, minimum: ProtocolVersion, maximum: ProtocolVersion) -> Self {
+    self.minimum_protocol_version = minimum;
+    self.maximum_protocol_version = maximum;
+    self
+  }
+
+  /// Protocol options, the startup parameters named `_pq_.*`, the server pretends to understand. Clients sending any other option are told
+  /// through NegotiateProtocolVersion that it was not recognized, which by default happens to every option, as with PostgreSQL.
+  /// * `options` - Full names of the options recognized, such as `_pq_.compression`.
+  pub fn with_protocol_options(mut self, options: Vec<String>) -> Self {
+    self.protocol_options = options;
+    self
+  }
+
+  /// Also accepts clients speaking protocol 2.0, whatever the range of protocol versions, serving them the simple query cycle. Off by
+  /// default, as in PostgreSQL 14 and later.
+  /// * `enabled` - Whether protocol 2.0 is accepted.
+  pub fn with_legacy_protocol(mut self, enabled: bool) -> Self {
+    self.legacy_protocol = enabled;
+    self
+  }
+
+  /// Salt the SCRAM-SHA-256 verifier of a password given in clear text is derived with, random for each connection by default. Has no
+  /// effect on passwords given as a verifier, which hold their own salt.
+  /// * `salt` - Salt sent to clients in the server-first-message.
+  pub fn with_scram_salt(mut self, salt: Vec<u8>) -> Self {
+    self.scram_salt = Some(salt);
+    self
+  }
+
+  /// Iteration count the SCRAM-SHA-256 verifier of a password given in clear text is derived with, 4096 by default as in PostgreSQL.
+  /// Has no effect on passwords given as a verifier, which hold their own iteration count.
+  /// * `iterations` - Number of times the password is hashed, at least 1 as clients refuse a count of 0.
+  pub fn with_scram_iterations(mut self, iterations: u32) -> Result<Self, GenericError> {
+    if iterations == 0 {
+      return Err("SCRAM iteration count must be at least 1".into());
+    }
+    self.scram_iterations = Some(iterations);
+    Ok(self)
+  }
+
+  /// Changes how SCRAM-SHA-256-PLUS is offered over TLS, which by default is offered and checked as PostgreSQL does.
+  /// * `channel_binding` - Behaviour of the server, deliberately wrong for anything but the default.
+  pub fn with_channel_binding(mut self, channel_binding: ChannelBinding) -> Self {
+    self.channel_binding = channel_binding;
+    self
+  }
+
+  /// Adds a role clients may connect as, replacing any role of the same name, including the superuser named after `user`. Clients naming
+  /// a role that does not exist are refused.
+  /// * `role` - Role and its attributes.
+  pub fn with_role(mut self, role: Role) -> Self {
+    self.roles.retain(|existing| existing.name != role.name);
+    self.roles.push(role);
+    self
+  }
+
+  /// Chooses the authentication method of each client with rules written as in `pg_hba.conf`, instead of using `authentication_type`
+  /// for everyone. The first rule matching the type of connection, database, user and address of the client applies, and clients no
+  /// rule matches are refused. In-memory connections count as `local`.
+  ///
+  /// Supported methods are `trust`, `reject`, `password`, `md5`, `scram-sha-256` and `cert`. Addresses are written in CIDR notation,
+  /// followed by a mask or as `all`, as host names are not resolved.
+  /// * `rules` - Contents of a `pg_hba.conf` file.
+  pub fn with_hba(mut self, rules: &str) -> Result<Self, GenericError> {
+    self.hba = Some(hba::parse(rules)?);
+    Ok(self)
+  }
+
+  /// Maps the common names of client certificates to the roles they may connect as, with entries written as in `pg_ident.conf`, used by
+  /// `cert` authentication when it names a map. System user names starting with `/` are regular expressions, whose first capture group
+  /// replaces `\1` in the role.
+  /// * `entries` - Contents of a `pg_ident.conf` file.
+  pub fn with_ident(mut self, entries: &str) -> Result<Self, GenericError> {
+    self.ident = ident::parse(entries)?;
+    Ok(self)
+  }
+
+  /// Asks clients for a certificate during the TLS handshake, which must have been issued by one of the CAs supplied, so `cert`
+  /// authentication can use it. Applies to TLS whether it is configured before or after, and has no effect without it.
+  /// * `certificates` - PEM encoded CA certificates.
+  pub fn with_client_ca(mut self, certificates: &[u8]) -> Result<Self, GenericError> {
+    self.client_verifier = Some(tls::client_verifier(certificates)?);
+    if let Some(tls) = self.tls.take() {
+      self.tls = Some(self.verify_clients(tls)?);
     }
+    Ok(self)
+  }
+
+  /// Asks clients for a certificate issued by the client CA, if one was supplied.
+  fn verify_clients(&self, tls: Tls) -> Result<Tls, GenericError> {
+    match &self.client_verifier {
+      Some(verifier) => tls.with_client_verifier(verifier.clone()),
+      None => Ok(tls),
+    }
+  }
+
+  /// Authenticates clients with custom code instead of `authentication_type`, which can delay, count or refuse attempts as it sees fit.
+  /// When `pg_hba.conf` rules are configured they choose the authentication method instead.
+  /// * `authenticator` - Code run for each client once its startup packet was accepted.
+  pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+    self.authenticator = Some(Arc::new(authenticator));
+    self
   }
 
   /// Database username.
@@ -93,6 +414,81 @@ impl Configuration {
   pub fn authentication_type(self) -> AuthenticationType {
     self.authentication_type
   }
+
+  /// Directory the Unix domain socket is created in.
+  pub fn socket_directory(self) -> Option<PathBuf> {
+    self.socket_directory
+  }
+
+  /// Whether the server accepts TLS connections.
+  pub fn tls(self) -> bool {
+    self.tls.is_some()
+  }
+
+  /// Whether clients may start the TLS handshake without sending SSLRequest first.
+  pub fn direct_tls(self) -> bool {
+    self.tls.is_some() && self.direct_tls
+  }
+
+  /// How GSSENCRequest is answered.
+  pub fn gss_encryption(self) -> GssEncryption {
+    self.gss_encryption
+  }
+
+  /// Oldest and newest protocol versions accepted.
+  pub fn protocol_versions(self) -> (ProtocolVersion, ProtocolVersion) {
+    (self.minimum_protocol_version, self.maximum_protocol_version)
+  }
+
+  /// Protocol options the server recognizes.
+  pub fn protocol_options(self) -> Vec<String> {
+    self.protocol_options
+  }
+
+  /// Whether clients speaking protocol 2.0 are accepted.
+  pub fn legacy_protocol(self) -> bool {
+    self.legacy_protocol
+  }
+
+  /// Salt used to derive SCRAM-SHA-256 verifiers, if not random.
+  pub fn scram_salt(self) -> Option<Vec<u8>> {
+    self.scram_salt
+  }
+
+  /// Iteration count used to derive SCRAM-SHA-256 verifiers, if not the default.
+  pub fn scram_iterations(self) -> Option<u32> {
+    self.scram_iterations
+  }
+
+  /// How SCRAM-SHA-256-PLUS is offered over TLS.
+  pub fn channel_binding(self) -> ChannelBinding {
+    self.channel_binding
+  }
+
+  /// Whether the authentication method is chosen by `pg_hba.conf` rules.
+  pub fn hba(self) -> bool {
+    self.hba.is_some()
+  }
+
+  /// Custom code authenticating clients, if any.
+  pub fn authenticator(self) -> Option<Arc<dyn Authenticator>> {
+    self.authenticator
+  }
+
+  /// Roles clients may connect as.
+  pub fn roles(self) -> Vec<Role> {
+    self.roles
+  }
+
+  /// Finds the role clients connecting as `name` log in as.
+  pub(crate) fn role(&self, name: &str) -> Option<&Role> {
+    self.roles.iter().find(|role| role.name == name)
+  }
+
+  /// PEM encoded certificate of the CA that issued the generated server certificate.
+  pub fn ca_certificate(self) -> Option<String> {
+    self.tls.and_then(|tls| tls.ca_certificate)
+  }
 }
 
 impl Default for Configuration {
@@ -104,49 +500,163 @@ impl Default for Configuration {
       dbname: None,
       hostaddr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 5432),
       authentication_type: AuthenticationType::Trust,
+      socket_directory: None,
+      socket_port: 0,
+      tls: None,
+      client_verifier: None,
+      direct_tls: true,
+      gss_encryption: GssEncryption::Refuse,
+      minimum_protocol_version: ProtocolVersion::V3_0,
+      maximum_protocol_version: ProtocolVersion::V3_0,
+      protocol_options: Vec::new(),
+      legacy_protocol: false,
+      scram_salt: None,
+      scram_iterations: None,
+      channel_binding: ChannelBinding::Offer,
+      roles: vec![superuser("postgres", None)],
+      hba: None,
+      ident: Vec::new(),
+      authenticator: None,
     }
   }
 }
 
+/// Role created along with the cluster, named after the configured user, which can log in with the configured password.
+fn superuser(user: &str, password: Option<String>) -> Role {
+  let role = Role::new(user.to_owned()).with_superuser(true);
+  match password {
+    Some(password) => role.with_password(password),
+    None => role,
+  }
+}
+
+/// Generates random bytes, for secret keys, salts and nonces.
+pub(crate) fn random_bytes(length: usize) -> Vec<u8> {
+  (0..length).map(|_| rand::random::<u8>()).collect()
+}
+
 /// Represents the Postmaster in the PostgreSQL architecture.
-/// It spawns a number of threads, each representing a backend process that in turn handles user commands.
+/// It spawns a number of tasks, each representing a backend process that in turn handles user commands.
 #[derive(Debug)]
-pub struct Postmaster<'a> {
+pub struct Postmaster {
   configuration: Configuration,
-  backends: Vec<Backend<'a>>,
+  backends: Vec<JoinHandle<()>>,
+  registry: Arc<Registry>,
   pid: u32,
 }
 
-impl<'a> Postmaster<'a> {
+impl Postmaster {
   /// Creates a new server instance with user defined settings.
   pub fn new(configuration: Configuration) -> Self {
+    let pid = process::id();
     Postmaster {
       configuration,
       backends: Vec::new(),
-      pid: process::id(),
+      registry: Registry::new(pid),
+      pid,
     }
   }
 
-  /// Starts a loop listening for messages from the client.
-  /// While PostgreSQL starts a new process once a client connects, we start a new thread and store it in a vector.
+  /// Starts listening for clients in a runtime of its own, running on a dedicated thread, and returns once the server accepts connections.
+  ///
+  /// Meant for synchronous callers that can't provide a runtime. The server stops when the handle is stopped or dropped.
+  pub fn start_in_background(self) -> Result<BackgroundServer, GenericError> {
+    let (ready, listening) = mpsc::channel::<Result<(SocketAddr, Option<PathBuf>, Option<String>), GenericError>>();
+    let (sender, receiver) = oneshot::channel::<()>();
+    let thread = thread::Builder::new().name("postmaster".to_owned()).spawn(move || {
+      let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+          let _ = ready.send(Err(e.into()));
+          return;
+        }
+      };
+      runtime.block_on(async move {
+        match self.spawn().await {
+          Ok(server) => {
+            let _ = ready.send(Ok((
+              server.address(),
+              server.socket_path().map(|path| path.to_path_buf()),
+              server.ca_certificate().map(str::to_owned),
+            )));
+            // Dropping the sender also stops the server.
+            let _ = receiver.await;
+            let _ = server.shutdown().await;
+          }
+          Err(e) => {
+            let _ = ready.send(Err(e));
+          }
+        }
+      });
+    })?;
+    let (address, socket_path, ca_certificate) = listening.recv()??;
+    Ok(BackgroundServer::new(address, socket_path, ca_certificate, sender, thread))
+  }
+
+  /// Starts listening for clients in a task of the current runtime and returns immediately.
+  /// While PostgreSQL starts a new process once a client connects, we spawn a new task and store its handle in a vector.
   /// Reference: https://www.postgresql.org/docs/14/connect-estab.html
-  #[tokio::main]
-  pub async fn start(self) -> Result<(), GenericError> {
+  ///
+  /// The configured port may be 0, in which case the operating system picks a free one that can be read from the returned handle.
+  /// The server stops when the handle is shut down or dropped.
+  pub async fn spawn(self) -> Result<ServerHandle, GenericError> {
+    let listener = Listener::bind(&self.configuration).await?;
+    let address = listener.local_addr();
+    let socket_path = listener.socket_path();
+    let ca_certificate = self.configuration.clone().ca_certificate();
+    let (sender, receiver) = oneshot::channel();
+    let task = tokio::spawn(self.serve(listener, receiver));
+    Ok(ServerHandle::new(address, socket_path, ca_certificate, sender, task))
+  }
+
+  /// Accepts clients until told to shut down, at which point every backend is stopped and the Unix domain socket removed.
+  async fn serve(self, listener: Listener, mut shutdown: oneshot::Receiver<()>) {
     // https://stackoverflow.com/a/55874334/70600
     let mut this = self;
+    let configuration = Arc::new(this.configuration.clone());
     loop {
-      let listener = TcpListener::bind(this.configuration.clone().hostaddr()).await?;
-      match listener.accept().await {
-        Ok((stream, _addr)) => {
-          let id = this.pid + 1;
-          let backend = Backend::new(&this.configuration, stream, id)?;
-          this.backends.push(backend);
-        }
-        // TODO Return or log and continue?
-        Err(e) => todo!("Log error accepting client connection."),
-      }
+      let stream = tokio::select! {
+        accepted = listener.accept() => match accepted {
+          Ok(stream) => stream,
+          // Failing to accept a client, for example because the process ran out of file descriptors, does not affect the other clients.
+          // Such errors usually persist for a while, so retrying at once would only spin.
+          Err(_) => {
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            continue;
+          }
+        },
+        // Dropping the sender also stops the server.
+        _ = &mut shutdown => break,
+      };
+      this.launch(&configuration, stream);
+    }
+    for backend in this.backends {
+      backend.abort();
     }
-    unreachable!()
+  }
+
+  /// Opens a connection to a new backend over an in-memory pipe instead of a socket, returning the client side of the pipe.
+  ///
+  /// Drivers generic over `AsyncRead + AsyncWrite` can use it to test against the mock without opening ports. The backend runs as a
+  /// task of the current runtime, so this panics if called outside of one.
+  pub fn connect_in_memory(&mut self) -> DuplexStream {
+    let (client, server) = io::duplex(DUPLEX_BUFFER_LENGTH);
+    let configuration = Arc::new(self.configuration.clone());
+    self.launch(&configuration, Stream::Duplex(server));
+    client
+  }
+
+  /// Spawns the task of the backend serving a client.
+  fn launch(&mut self, configuration: &Arc<Configuration>, stream: Stream) {
+    let backend = match Backend::new(configuration.clone(), stream, self.registry.register()) {
+      Ok(backend) => backend,
+      Err(_) => return,
+    };
+    self.backends.retain(|backend| !backend.is_finished());
+    self.backends.push(tokio::spawn(async move {
+      // Errors were already reported to the client and only end this connection.
+      let _ = backend.run().await;
+    }));
   }
 
   /// Returns the configuration used to create the server.
@@ -165,14 +675,10 @@ impl<'a> Postmaster<'a> {
   }
 }
 
-impl<'a> Default for Postmaster<'a> {
+impl Default for Postmaster {
   /// Creates a server instance with default settings.
   fn default() -> Self {
-    Postmaster {
-      configuration: Configuration::default(),
-      backends: Vec::new(),
-      pid: std::process::id(),
-    }
+    Postmaster::new(Configuration::default())
   }
 }
 
@@ -180,6 +686,10 @@ impl<'a> Default for Postmaster<'a> {
 mod tests {
 
   use super::{AuthenticationType, Configuration, Postmaster};
+  // Used by the integration tests only.
+  use postgres as _;
+  use tokio_postgres as _;
+  use tokio_postgres_rustls as _;
 
   #[test]
   fn test_default_configuration() {
@@ -187,6 +697,12 @@ mod tests {
     assert_eq!(configuration.authentication_type, AuthenticationType::Trust)
   }
 
+  #[test]
+  fn test_scram_iterations() {
+    assert_eq!(Configuration::default().with_scram_iterations(1).unwrap().scram_iterations, Some(1));
+    assert!(Configuration::default().with_scram_iterations(0).is_err());
+  }
+
   #[test]
   fn test_default_server() {
     let server = Postmaster::default();
@@ -0,0 +1,82 @@
+//! Sockets the Postmaster accepts clients on.
+
+use crate::stream::Stream;
+use crate::Configuration;
+use std::future;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+/// TCP socket, optionally paired with a Unix domain socket when the configuration names a socket directory.
+///
+/// The Unix domain socket file is removed when the listener is dropped.
+#[derive(Debug)]
+pub(crate) struct Listener {
+  tcp: TcpListener,
+  unix: Option<(UnixListener, PathBuf)>,
+}
+
+impl Listener {
+  /// Binds every socket required by the configuration.
+  pub(crate) async fn bind(configuration: &Configuration) -> io::Result<Self> {
+    let tcp = TcpListener::bind(configuration.hostaddr).await?;
+    let unix = match &configuration.socket_directory {
+      Some(directory) => {
+        let port = match configuration.socket_port {
+          0 => tcp.local_addr()?.port(),
+          port => port,
+        };
+        let path = directory.join(format!(".s.PGSQL.{}", port));
+        Some((bind_unix(&path).await?, path))
+      }
+      None => None,
+    };
+    Ok(Listener { tcp, unix })
+  }
+
+  /// Address of the TCP socket.
+  pub(crate) fn local_addr(&self) -> std::net::SocketAddr {
+    self.tcp.local_addr().expect("Bound socket without an address.")
+  }
+
+  /// Path of the Unix domain socket.
+  pub(crate) fn socket_path(&self) -> Option<PathBuf> {
+    self.unix.as_ref().map(|(_, path)| path.clone())
+  }
+
+  /// Waits for a client on any of the sockets.
+  pub(crate) async fn accept(&self) -> io::Result<Stream> {
+    let unix = async {
+      match &self.unix {
+        Some((listener, _)) => listener.accept().await,
+        None => future::pending().await,
+      }
+    };
+    tokio::select! {
+      accepted = self.tcp.accept() => accepted.map(|(stream, _)| Stream::Tcp(stream)),
+      accepted = unix => accepted.map(|(stream, _)| Stream::Unix(stream)),
+    }
+  }
+}
+
+/// Binds a Unix domain socket, replacing the file left behind by a server that did not shut down cleanly.
+async fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+  if path.exists() {
+    if UnixStream::connect(path).await.is_ok() {
+      return Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("another server is already listening in {}", path.display()),
+      ));
+    }
+    fs::remove_file(path)?;
+  }
+  UnixListener::bind(path)
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    if let Some((_, path)) = &self.unix {
+      let _ = fs::remove_file(path);
+    }
+  }
+}
@@ -0,0 +1,138 @@
+//! Holds the roles clients may connect as, the mock's counterpart of `pg_authid`.
+//! Reference: https://www.postgresql.org/docs/14/sql-createrole.html
+
+use std::time::SystemTime;
+
+/// Role a client names in the startup packet, with the attributes checked while it connects.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Role {
+  pub(crate) name: String,
+  password: Option<String>,
+  pub(crate) login: bool,
+  valid_until: Option<SystemTime>,
+  pub(crate) connection_limit: Option<u32>,
+  pub(crate) superuser: bool,
+  pub(crate) member_of: Vec<String>,
+}
+
+impl Role {
+  /// Creates a role that can log in without a password, with no expiry, no connection limit and no superuser status, like
+  /// `CREATE USER <name>`.
+  /// * `name` - Name clients connect as.
+  pub fn new(name: String) -> Self {
+    Role {
+      name,
+      password: None,
+      login: true,
+      valid_until: None,
+      connection_limit: None,
+      superuser: false,
+      member_of: Vec::new(),
+    }
+  }
+
+  /// Sets the password checked by password authentication, like `PASSWORD`.
+  /// * `password` - Password in clear text, hashed with MD5 as `md5` followed by 32 hexadecimal digits, or a SCRAM-SHA-256 verifier
+  ///   written `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>`.
+  pub fn with_password(mut self, password: String) -> Self {
+    self.password = Some(password);
+    self
+  }
+
+  /// Whether the role may connect, like `LOGIN` and `NOLOGIN`.
+  /// * `login` - Whether clients may connect as the role.
+  pub fn with_login(mut self, login: bool) -> Self {
+    self.login = login;
+    self
+  }
+
+  /// Time after which password authentication fails, like `VALID UNTIL`. Authentication methods that do not use the password ignore it.
+  /// * `valid_until` - Expiry of the password.
+  pub fn with_valid_until(mut self, valid_until: SystemTime) -> Self {
+    self.valid_until = Some(valid_until);
+    self
+  }
+
+  /// Limits how many clients may be connected as the role at the same time, like `CONNECTION LIMIT`. Superusers are not limited.
+  /// * `limit` - Concurrent connections allowed.
+  pub fn with_connection_limit(mut self, limit: u32) -> Self {
+    self.connection_limit = Some(limit);
+    self
+  }
+
+  /// Whether the role bypasses every check but the login flag, like `SUPERUSER`, reported to clients through the `is_superuser`
+  /// parameter.
+  /// * `superuser` - Whether the role is a superuser.
+  pub fn with_superuser(mut self, superuser: bool) -> Self {
+    self.superuser = superuser;
+    self
+  }
+
+  /// Makes the role a member of a group, like `IN ROLE`, which `pg_hba.conf` rules match with `+group` and `samerole`.
+  /// * `group` - Name of the role the role belongs to.
+  pub fn with_member_of(mut self, group: String) -> Self {
+    self.member_of.push(group);
+    self
+  }
+
+  /// Name clients connect as.
+  pub fn name(self) -> String {
+    self.name
+  }
+
+  /// Password checked by password authentication.
+  pub fn password(self) -> Option<String> {
+    self.password
+  }
+
+  /// Whether clients may connect as the role.
+  pub fn login(self) -> bool {
+    self.login
+  }
+
+  /// Expiry of the password.
+  pub fn valid_until(self) -> Option<SystemTime> {
+    self.valid_until
+  }
+
+  /// Concurrent connections allowed.
+  pub fn connection_limit(self) -> Option<u32> {
+    self.connection_limit
+  }
+
+  /// Whether the role is a superuser.
+  pub fn superuser(self) -> bool {
+    self.superuser
+  }
+
+  /// Groups the role belongs to directly.
+  pub fn member_of(self) -> Vec<String> {
+    self.member_of
+  }
+
+  /// Password usable for authentication, which is none once it expired.
+  pub(crate) fn valid_password(&self) -> Option<&str> {
+    match self.valid_until {
+      Some(valid_until) if valid_until <= SystemTime::now() => None,
+      _ => self.password.as_deref(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::Role;
+  use std::time::{Duration, SystemTime};
+
+  #[test]
+  fn test_valid_password() {
+    let role = Role::new("alice".to_owned()).with_password("secret".to_owned());
+    assert_eq!(role.valid_password(), Some("secret"));
+    let role = role.with_valid_until(SystemTime::now() + Duration::from_secs(3600));
+    assert_eq!(role.valid_password(), Some("secret"));
+    let role = role.with_valid_until(SystemTime::now() - Duration::from_secs(1));
+    assert_eq!(role.valid_password(), None);
+    assert_eq!(role.password(), Some("secret".to_owned()));
+  }
+}
@@ -1,10 +1,14 @@
-//! Abstraction between TCP and BSD sockets.
+//! Abstraction between TCP sockets, BSD sockets, in-memory pipes and TLS.
 
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use tokio::{
   io,
-  io::AsyncWriteExt,
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf},
   net::{TcpStream, UnixStream},
 };
+use tokio_rustls::server::TlsStream;
 
 /// Converts a slice of 4 bytes to an array of 4 bytes.
 #[inline]
@@ -12,32 +16,128 @@ pub(crate) fn slice_to_array(slice: &[u8]) -> [u8; 4] {
   slice.try_into().expect("Slice with incorrect length.")
 }
 
-/// Abstraction over TCP and BSD streams.
+/// Abstraction over TCP and BSD streams, as well as in-memory pipes used to talk to a backend without opening a socket.
+///
+/// Any of them can be wrapped in TLS once the client asks for an encrypted connection.
 #[derive(Debug)]
 pub(crate) enum Stream {
   Tcp(TcpStream),
   Unix(UnixStream),
+  Duplex(DuplexStream),
+  Tls(Box<TlsStream<Stream>>),
+  /// Stream whose first bytes were read to find out what the client is sending, handed out again before anything else is read.
+  Rewound {
+    prefix: Vec<u8>,
+    stream: Box<Stream>,
+  },
 }
 
 impl Stream {
-  pub(crate) fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
-    match self {
-      Stream::Tcp(value) => value.try_read(buf),
-      Stream::Unix(value) => value.try_read(buf),
+  /// Reads from the stream until `buf` is full or the peer closes the connection.
+  ///
+  /// A single read may return only part of what the client sent, since packets can be split over several TCP segments, so this keeps
+  /// reading until enough bytes arrived. Returns the number of bytes read, which is smaller than the length of `buf` only if the connection
+  /// was closed.
+  pub(crate) async fn read_full(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+      match self.read(&mut buf[filled..]).await? {
+        0 => break,
+        n => filled += n,
+      }
     }
+    Ok(filled)
   }
 
-  pub(crate) async fn readable(&self) -> io::Result<()> {
+  /// Address of the client, which only TCP connections have.
+  pub(crate) fn peer_address(&self) -> Option<IpAddr> {
     match self {
-      Stream::Tcp(value) => Ok(value.readable().await?),
-      Stream::Unix(value) => Ok(value.readable().await?),
+      Stream::Tcp(stream) => stream.peer_addr().ok().map(|address| address.ip()),
+      Stream::Tls(stream) => stream.get_ref().0.peer_address(),
+      Stream::Rewound { stream, .. } => stream.peer_address(),
+      Stream::Unix(_) | Stream::Duplex(_) => None,
     }
   }
+}
 
-  pub(crate) async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-    match self {
-      Stream::Tcp(value) => Ok(value.write_all(buf).await?),
-      Stream::Unix(value) => Ok(value.write_all(buf).await?),
+impl AsyncRead for Stream {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Stream::Tcp(value) => Pin::new(value).poll_read(cx, buf),
+      Stream::Unix(value) => Pin::new(value).poll_read(cx, buf),
+      Stream::Duplex(value) => Pin::new(value).poll_read(cx, buf),
+      Stream::Tls(value) => Pin::new(value.as_mut()).poll_read(cx, buf),
+      Stream::Rewound { prefix, stream } => {
+        if prefix.is_empty() {
+          return Pin::new(stream.as_mut()).poll_read(cx, buf);
+        }
+        let length = prefix.len().min(buf.remaining());
+        buf.put_slice(&prefix[..length]);
+        let _ = prefix.drain(..length);
+        Poll::Ready(Ok(()))
+      }
+    }
+  }
+}
+
+impl AsyncWrite for Stream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Stream::Tcp(value) => Pin::new(value).poll_write(cx, buf),
+      Stream::Unix(value) => Pin::new(value).poll_write(cx, buf),
+      Stream::Duplex(value) => Pin::new(value).poll_write(cx, buf),
+      Stream::Tls(value) => Pin::new(value.as_mut()).poll_write(cx, buf),
+      Stream::Rewound { stream, .. } => Pin::new(stream.as_mut()).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Stream::Tcp(value) => Pin::new(value).poll_flush(cx),
+      Stream::Unix(value) => Pin::new(value).poll_flush(cx),
+      Stream::Duplex(value) => Pin::new(value).poll_flush(cx),
+      Stream::Tls(value) => Pin::new(value.as_mut()).poll_flush(cx),
+      Stream::Rewound { stream, .. } => Pin::new(stream.as_mut()).poll_flush(cx),
     }
   }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Stream::Tcp(value) => Pin::new(value).poll_shutdown(cx),
+      Stream::Unix(value) => Pin::new(value).poll_shutdown(cx),
+      Stream::Duplex(value) => Pin::new(value).poll_shutdown(cx),
+      Stream::Tls(value) => Pin::new(value.as_mut()).poll_shutdown(cx),
+      Stream::Rewound { stream, .. } => Pin::new(stream.as_mut()).poll_shutdown(cx),
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+  use super::Stream;
+  use tokio::io::AsyncWriteExt;
+  use tokio::net::{TcpListener, TcpStream};
+
+  /// Opens a TCP connection on an ephemeral port and returns the server side as a `Stream` together with the client socket.
+  pub(crate) async fn connected_pair() -> (Stream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (Stream::Tcp(server), client)
+  }
+
+  #[tokio::test]
+  async fn test_rewound() {
+    let (server, mut client) = connected_pair().await;
+    client.write_all(b"world").await.unwrap();
+    let mut stream = Stream::Rewound {
+      prefix: b"hello ".to_vec(),
+      stream: Box::new(server),
+    };
+    let mut buf = [0u8; 11];
+    assert_eq!(stream.read_full(&mut buf).await.unwrap(), 11);
+    assert_eq!(&buf, b"hello world");
+    assert_eq!(stream.peer_address(), Some(client.local_addr().unwrap().ip()));
+  }
 }
@@ -0,0 +1,297 @@
+//! Holds the code necessary to encrypt connections with TLS.
+
+use crate::stream::Stream;
+use crate::GenericError;
+use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, SanType};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::oid_registry::{
+  Oid, OID_PKCS1_SHA384WITHRSA, OID_PKCS1_SHA512WITHRSA, OID_SIG_ECDSA_WITH_SHA384, OID_SIG_ECDSA_WITH_SHA512, OID_SIG_ED25519,
+};
+use x509_parser::parse_x509_certificate;
+
+/// Protocol clients connecting with direct TLS must negotiate through ALPN.
+pub(crate) static ALPN_PROTOCOL: &[u8] = b"postgresql";
+/// Signature algorithms whose hash function is also used for `tls-server-end-point`. Any other algorithm uses SHA-256, as RFC 5929
+/// requires for MD5 and SHA-1.
+static SHA_384_SIGNATURES: [Oid<'static>; 2] = [OID_PKCS1_SHA384WITHRSA, OID_SIG_ECDSA_WITH_SHA384];
+static SHA_512_SIGNATURES: [Oid<'static>; 3] = [OID_PKCS1_SHA512WITHRSA, OID_SIG_ECDSA_WITH_SHA512, OID_SIG_ED25519];
+
+/// TLS settings of the server.
+#[derive(Debug, Clone)]
+pub(crate) struct Tls {
+  pub(crate) server_config: Arc<ServerConfig>,
+  /// PEM of the CA that issued the server certificate, when the certificate was generated.
+  pub(crate) ca_certificate: Option<String>,
+  /// Channel binding data of type `tls-server-end-point`, the hash of the server certificate SCRAM-SHA-256-PLUS binds exchanges to.
+  pub(crate) server_end_point: Vec<u8>,
+  certificates: Vec<CertificateDer<'static>>,
+  key: Arc<PrivateKeyDer<'static>>,
+}
+
+impl Tls {
+  /// Builds the server side TLS configuration out of PEM encoded material.
+  /// * `certificate_chain` - Server certificate followed by any intermediate certificates.
+  /// * `private_key` - Key of the server certificate, in PKCS#1, PKCS#8 or SEC1 format.
+  pub(crate) fn new(certificate_chain: &[u8], private_key: &[u8]) -> Result<Self, GenericError> {
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(certificate_chain)).collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+    if certificates.is_empty() {
+      return Err("no certificate found in the certificate chain".into());
+    }
+    let key = private_key_der(private_key)?;
+    Ok(Tls {
+      server_config: server_config(&certificates, &key, WebPkiClientVerifier::no_client_auth())?,
+      ca_certificate: None,
+      server_end_point: server_end_point(&certificates[0]),
+      certificates,
+      key: Arc::new(key),
+    })
+  }
+
+  /// Asks clients for a certificate during the handshake, checked by the verifier supplied.
+  /// * `verifier` - Verifier built out of the client CA.
+  pub(crate) fn with_client_verifier(mut self, verifier: Arc<dyn ClientCertVerifier>) -> Result<Self, GenericError> {
+    self.server_config = server_config(&self.certificates, &self.key, verifier)?;
+    Ok(self)
+  }
+
+  /// Creates a throwaway CA and a server certificate it signed, valid for `localhost` and the address the server listens in.
+  ///
+  /// Clients that trust the CA can connect with `sslmode=verify-full`.
+  pub(crate) fn generate(hostaddr: &SocketAddr) -> Result<Self, GenericError> {
+    let ca_key = KeyPair::generate()?;
+    let mut ca_params = CertificateParams::new(Vec::new())?;
+    ca_params.distinguished_name = DistinguishedName::new();
+    ca_params.distinguished_name.push(DnType::CommonName, "rustgres CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key)?;
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec!["localhost".to_owned()])?;
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, "localhost");
+    // A server listening in every interface is usually reached through the loopback.
+    let addresses = match hostaddr.ip() {
+      ip if ip.is_unspecified() => vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+      ip => vec![ip],
+    };
+    params.subject_alt_names.extend(addresses.into_iter().map(SanType::IpAddress));
+    let certificate = params.signed_by(&key, &ca, &ca_key)?;
+
+    let mut tls = Tls::new(certificate.pem().as_bytes(), key.serialize_pem().as_bytes())?;
+    tls.ca_certificate = Some(ca.pem());
+    Ok(tls)
+  }
+}
+
+/// Builds the verifier refusing client certificates that were not issued by one of the CAs supplied. Clients that send none are still
+/// accepted, leaving it to authentication to require one.
+/// * `certificates` - PEM encoded CA certificates.
+pub(crate) fn client_verifier(certificates: &[u8]) -> Result<Arc<dyn ClientCertVerifier>, GenericError> {
+  let mut roots = RootCertStore::empty();
+  for certificate in rustls_pemfile::certs(&mut BufReader::new(certificates)) {
+    roots.add(certificate?)?;
+  }
+  if roots.is_empty() {
+    return Err("no certificate found in the client CA".into());
+  }
+  let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(ring::default_provider()))
+    .allow_unauthenticated()
+    .build()?;
+  Ok(verifier)
+}
+
+/// Builds the server side TLS configuration.
+fn server_config(
+  certificates: &[CertificateDer<'static>],
+  key: &PrivateKeyDer<'static>,
+  verifier: Arc<dyn ClientCertVerifier>,
+) -> Result<Arc<ServerConfig>, GenericError> {
+  // The provider is chosen explicitly so the configuration does not depend on the process wide default.
+  let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+    .with_safe_default_protocol_versions()?
+    .with_client_cert_verifier(verifier)
+    .with_single_cert(certificates.to_vec(), key.clone_key())?;
+  // Like PostgreSQL 17, clients offering other protocols are refused while clients offering none are accepted.
+  config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+  Ok(Arc::new(config))
+}
+
+/// Hashes a certificate for `tls-server-end-point` channel binding, with the hash function of its signature algorithm.
+fn server_end_point(certificate: &[u8]) -> Vec<u8> {
+  match signature_algorithm(certificate) {
+    Some(algorithm) if SHA_384_SIGNATURES.contains(&algorithm) => Sha384::digest(certificate).to_vec(),
+    Some(algorithm) if SHA_512_SIGNATURES.contains(&algorithm) => Sha512::digest(certificate).to_vec(),
+    _ => Sha256::digest(certificate).to_vec(),
+  }
+}
+
+/// Finds the OID of the signature algorithm of a DER encoded certificate.
+fn signature_algorithm(certificate: &[u8]) -> Option<Oid<'_>> {
+  let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+  Some(certificate.signature_algorithm.algorithm)
+}
+
+/// Reads the common name in the subject of a DER encoded certificate, which `cert` authentication takes as the name of the user.
+pub(crate) fn common_name(certificate: &[u8]) -> Option<String> {
+  let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+  let name = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+  Some(name.to_owned())
+}
+
+/// Reads the first private key of a PEM file.
+fn private_key_der(private_key: &[u8]) -> Result<PrivateKeyDer<'static>, GenericError> {
+  for item in rustls_pemfile::read_all(&mut BufReader::new(private_key)) {
+    match item? {
+      Item::Pkcs1Key(key) => return Ok(key.into()),
+      Item::Pkcs8Key(key) => return Ok(key.into()),
+      Item::Sec1Key(key) => return Ok(key.into()),
+      _ => continue,
+    }
+  }
+  Err("no private key found".into())
+}
+
+impl Stream {
+  /// Performs the TLS handshake, returning a stream that encrypts everything sent over the original one.
+  pub(crate) async fn upgrade(self, config: Arc<ServerConfig>) -> io::Result<Stream> {
+    let stream = TlsAcceptor::from(config).accept(self).await?;
+    Ok(Stream::Tls(Box::new(stream)))
+  }
+
+  /// Whether the connection is encrypted.
+  pub(crate) fn is_tls(&self) -> bool {
+    matches!(self, Stream::Tls(_))
+  }
+
+  /// Certificate the client sent during the TLS handshake, already verified against the client CA.
+  pub(crate) fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+    match self {
+      Stream::Tls(stream) => stream.get_ref().1.peer_certificates()?.first(),
+      _ => None,
+    }
+  }
+
+  /// Protocol the client negotiated through ALPN during the TLS handshake.
+  pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+    match self {
+      Stream::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{common_name, server_end_point, signature_algorithm, Tls};
+  use crate::stream::Stream;
+  use rcgen::{CertificateParams, CertifiedKey, DistinguishedName, DnType, KeyPair};
+  use sha2::{Digest, Sha256, Sha384};
+  use std::net::SocketAddr;
+  use std::sync::Arc;
+  use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+  use tokio_rustls::rustls::crypto::ring;
+  use tokio_rustls::rustls::pki_types::ServerName;
+  use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+  use tokio_rustls::TlsConnector;
+  use x509_parser::oid_registry::OID_SIG_ECDSA_WITH_SHA256;
+
+  #[test]
+  fn test_server_config() {
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    assert!(Tls::new(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes()).is_ok());
+
+    // Key and certificate swapped.
+    assert!(Tls::new(key_pair.serialize_pem().as_bytes(), cert.pem().as_bytes()).is_err());
+
+    // Key that does not match the certificate.
+    let other = KeyPair::generate().unwrap();
+    assert!(Tls::new(cert.pem().as_bytes(), other.serialize_pem().as_bytes()).is_err());
+  }
+
+  #[test]
+  fn test_generate() {
+    let address: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+    let tls = Tls::generate(&address).unwrap();
+    let ca = tls.ca_certificate.unwrap();
+    assert!(ca.starts_with("-----BEGIN CERTIFICATE-----"));
+    assert_eq!(tls.server_end_point.len(), 32);
+  }
+
+  #[test]
+  fn test_server_end_point() {
+    // rcgen signs with ECDSA P-256 and SHA-256 by default.
+    let CertifiedKey { cert, .. } = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    assert_eq!(signature_algorithm(cert.der()), Some(OID_SIG_ECDSA_WITH_SHA256));
+    assert_eq!(server_end_point(cert.der()), Sha256::digest(cert.der()).to_vec());
+
+    let key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_owned()]).unwrap().self_signed(&key).unwrap();
+    assert_eq!(server_end_point(cert.der()), Sha384::digest(cert.der()).to_vec());
+
+    assert_eq!(signature_algorithm(b"garbage"), None);
+    assert_eq!(signature_algorithm(&cert.der()[..cert.der().len() - 1]), None);
+  }
+
+  #[test]
+  fn test_common_name() {
+    let key = KeyPair::generate().unwrap();
+    let certificate = |names: &[(DnType, &str)]| {
+      let mut params = CertificateParams::new(Vec::new()).unwrap();
+      params.distinguished_name = DistinguishedName::new();
+      for (name, value) in names {
+        params.distinguished_name.push(name.clone(), *value);
+      }
+      params.self_signed(&key).unwrap()
+    };
+    let cert = certificate(&[(DnType::OrganizationName, "rustgres"), (DnType::CommonName, "alice")]);
+    assert_eq!(common_name(cert.der()), Some("alice".to_owned()));
+    let cert = certificate(&[(DnType::OrganizationName, "rustgres")]);
+    assert_eq!(common_name(cert.der()), None);
+    assert_eq!(common_name(b"garbage"), None);
+    let cert = certificate(&[(DnType::CommonName, "alice")]);
+    assert_eq!(common_name(&cert.der()[..cert.der().len() - 1]), None);
+  }
+
+  #[tokio::test]
+  async fn test_upgrade() {
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let config = Tls::new(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes())
+      .unwrap()
+      .server_config;
+    let (client, server) = io::duplex(64 * 1024);
+    let server = tokio::spawn(async move {
+      let mut stream = Stream::Duplex(server).upgrade(config).await.unwrap();
+      assert!(stream.is_tls());
+      let mut buf = [0u8; 5];
+      let _ = stream.read_exact(&mut buf).await.unwrap();
+      stream.write_all(&buf).await.unwrap();
+      stream.flush().await.unwrap();
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let mut client = connector.connect(ServerName::try_from("localhost").unwrap(), client).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    let _ = client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    server.await.unwrap();
+  }
+}
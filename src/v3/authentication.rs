@@ -0,0 +1,518 @@
+//! Holds the code necessary to authenticate the user once the startup packet was accepted.
+//! Reference: https://www.postgresql.org/docs/14/auth-password.html
+
+use super::authenticator::{AuthenticationClient, AuthenticationFuture, Authenticator};
+use super::backend::BackendFrames;
+use super::frontend::{Cursor, FramedStream, FrontEndFrames};
+use super::scram::{self, Binding, Exchange, Verifier, SCRAM_SHA_256, SCRAM_SHA_256_PLUS};
+use crate::errors::Errors;
+use crate::ident;
+use crate::stream::slice_to_array;
+use crate::tls::common_name;
+use crate::{random_bytes, AuthenticationType, ChannelBinding, Configuration, Role};
+use md5::{Digest, Md5};
+use std::io;
+
+/// Prefix of passwords stored as an MD5 hash, followed by 32 hexadecimal digits.
+static MD5_PREFIX: &str = "md5";
+
+impl Authenticator for AuthenticationType {
+  /// Performs the authentication handshake of the type.
+  ///
+  /// As in PostgreSQL, roles that do not exist or whose password is missing or expired fail password authentication the same way a wrong
+  /// password does, so clients can't tell which roles exist.
+  fn authenticate<'a>(&'a self, client: &'a mut AuthenticationClient<'_>) -> AuthenticationFuture<'a> {
+    Box::pin(async move {
+      let user = client.user().to_owned();
+      Ok(authenticate(client.stream, client.configuration, self, &user).await?)
+    })
+  }
+}
+
+/// Performs the authentication handshake of a built-in authentication type, leaving AuthenticationOk to the caller.
+/// * `stream` - Connection to the client.
+/// * `configuration` - Configuration used to define the backend process.
+/// * `method` - Authentication method chosen for the client.
+/// * `user` - User named in the startup packet.
+async fn authenticate(stream: &mut FramedStream, configuration: &Configuration, method: &AuthenticationType, user: &str) -> Result<(), Errors> {
+  let stored = configuration.role(user).and_then(Role::valid_password);
+  match method {
+    AuthenticationType::Trust => {}
+    AuthenticationType::AuthenticationCleartextPassword => {
+      stream.send(&[BackendFrames::AuthenticationCleartextPassword]).await?;
+      let password = password(read_password_message(stream).await?)?;
+      let valid = match stored {
+        // The stored hash can be checked against the password the client sent.
+        Some(stored) if is_md5_hash(stored) => md5_hash(&password, user) == stored,
+        Some(stored) => match Verifier::parse(stored) {
+          Some(verifier) => verifier.verify_password(&password),
+          None => stored == password,
+        },
+        None => false,
+      };
+      if !valid {
+        return Err(Errors::InvalidPassword { user: user.to_owned() });
+      }
+    }
+    // As in PostgreSQL, a password stored as a SCRAM verifier can't answer an MD5 challenge so SCRAM is used instead.
+    AuthenticationType::AuthenticationMD5Password if stored.and_then(Verifier::parse).is_some() => sasl(stream, configuration, user, stored).await?,
+    AuthenticationType::AuthenticationMD5Password => {
+      let salt = slice_to_array(&random_bytes(4));
+      stream.send(&[BackendFrames::AuthenticationMD5Password { salt }]).await?;
+      let response = password(read_password_message(stream).await?)?;
+      let valid = match stored {
+        Some(stored) if is_md5_hash(stored) => salted_md5(stored, &salt) == response,
+        Some(stored) => salted_md5(&md5_hash(stored, user), &salt) == response,
+        None => false,
+      };
+      if !valid {
+        return Err(Errors::InvalidPassword { user: user.to_owned() });
+      }
+    }
+    AuthenticationType::AuthenticationSASL => sasl(stream, configuration, user, stored).await?,
+    AuthenticationType::AuthenticationGSS { tokens } => gss(stream, user, tokens).await?,
+    AuthenticationType::Certificate { map } => certificate(stream, configuration, map.as_deref(), user)?,
+    // pg_hba.conf rules that reject clients are refused before authentication, so only authenticators get here.
+    AuthenticationType::Reject => {
+      return Err(Errors::InvalidAuthorizationSpecification {
+        message: format!("connection rejected for user \"{}\"", user),
+      })
+    }
+  }
+  Ok(())
+}
+
+/// Checks the certificate the client sent during the TLS handshake, whose common name must be the user or be mapped to it.
+fn certificate(stream: &FramedStream, configuration: &Configuration, map: Option<&str>, user: &str) -> Result<(), Errors> {
+  let certificate = stream
+    .get_ref()
+    .client_certificate()
+    .ok_or_else(|| Errors::InvalidAuthorizationSpecification {
+      message: "connection requires a valid client certificate".to_owned(),
+    })?;
+  let common_name = common_name(certificate).ok_or_else(|| Errors::InvalidAuthorizationSpecification {
+    message: format!(
+      "certificate authentication failed for user \"{}\": client certificate contains no user name",
+      user
+    ),
+  })?;
+  let valid = match map {
+    Some(map) => ident::check(configuration, map, user, &common_name),
+    None => common_name == user,
+  };
+  if !valid {
+    return Err(Errors::InvalidAuthorizationSpecification {
+      message: format!("certificate authentication failed for user \"{}\"", user),
+    });
+  }
+  Ok(())
+}
+
+/// Goes through a scripted GSSAPI negotiation, from AuthenticationGSS to the last AuthenticationGSSContinue, failing as soon as the
+/// client sends a token other than the one expected.
+async fn gss(stream: &mut FramedStream, user: &str, tokens: &[(Vec<u8>, Vec<u8>)]) -> Result<(), Errors> {
+  stream.send(&[BackendFrames::AuthenticationGSS]).await?;
+  if tokens.is_empty() {
+    // The client answers AuthenticationGSS with a token whatever the script, which an empty one accepts as is.
+    let _ = read_password_message(stream).await?;
+  }
+  for (index, (expected, reply)) in tokens.iter().enumerate() {
+    if read_password_message(stream).await? != *expected {
+      return Err(Errors::InvalidAuthorizationSpecification {
+        message: format!("GSSAPI authentication failed for user \"{}\"", user),
+      });
+    }
+    // The client only sends its next token once answered, even with an empty one.
+    if !reply.is_empty() || index + 1 < tokens.len() {
+      stream.send(&[BackendFrames::AuthenticationGSSContinue { data: reply.clone() }]).await?;
+    }
+  }
+  Ok(())
+}
+
+/// Goes through the SCRAM-SHA-256 exchange, from AuthenticationSASL to AuthenticationSASLFinal, offering SCRAM-SHA-256-PLUS first over
+/// TLS.
+async fn sasl(stream: &mut FramedStream, configuration: &Configuration, user: &str, stored: Option<&str>) -> Result<(), Errors> {
+  let server_end_point = match &configuration.tls {
+    Some(tls) if stream.is_tls() && configuration.channel_binding != ChannelBinding::Omit => Some(tls.server_end_point.clone()),
+    _ => None,
+  };
+  let mut mechanisms = vec![SCRAM_SHA_256.to_owned()];
+  if server_end_point.is_some() {
+    mechanisms.insert(0, SCRAM_SHA_256_PLUS.to_owned());
+  }
+  stream.send(&[BackendFrames::AuthenticationSASL { mechanisms }]).await?;
+  let (mechanism, client_first) = initial_response(read_password_message(stream).await?)?;
+  let binding = match server_end_point {
+    Some(data) if mechanism == SCRAM_SHA_256_PLUS => Binding::Selected(data),
+    Some(_) if mechanism == SCRAM_SHA_256 => Binding::Offered,
+    None if mechanism == SCRAM_SHA_256 => Binding::Unsupported,
+    _ => {
+      return Err(Errors::ProtocolViolation {
+        message: "client selected an invalid SASL authentication mechanism".to_owned(),
+      })
+    }
+  };
+  let wrong_binding = configuration.channel_binding == ChannelBinding::Wrong && matches!(binding, Binding::Selected(_));
+  let (mut exchange, server_first) = Exchange::start(verifier(configuration, stored), user, binding, &client_first)?;
+  if wrong_binding {
+    exchange = exchange.with_wrong_binding();
+  }
+  stream
+    .send(&[BackendFrames::AuthenticationSASLContinue {
+      data: server_first.into_bytes(),
+    }])
+    .await?;
+  let server_final = exchange.finish(&read_password_message(stream).await?)?;
+  stream
+    .send(&[BackendFrames::AuthenticationSASLFinal {
+      data: server_final.into_bytes(),
+    }])
+    .await?;
+  Ok(())
+}
+
+/// Verifier the client must prove it knows the password of.
+///
+/// A password stored in clear text is derived with the configured salt and iteration count. Roles without a usable password, or whose
+/// password is hashed with MD5, get a verifier no password matches so the exchange fails only once the client sends its proof.
+fn verifier(configuration: &Configuration, stored: Option<&str>) -> Verifier {
+  match stored {
+    Some(stored) if !is_md5_hash(stored) => Verifier::parse(stored).unwrap_or_else(|| {
+      let salt = configuration
+        .scram_salt
+        .clone()
+        .unwrap_or_else(|| random_bytes(scram::DEFAULT_SALT_LENGTH));
+      Verifier::new(stored, &salt, configuration.scram_iterations.unwrap_or(scram::DEFAULT_ITERATIONS))
+    }),
+    _ => Verifier::mock(),
+  }
+}
+
+/// Reads a SASLInitialResponse, made of the name of the mechanism chosen followed by the length of the initial response and its bytes.
+fn initial_response(data: Vec<u8>) -> Result<(String, Vec<u8>), Errors> {
+  let mut cursor = Cursor::new(&data);
+  let mechanism = cursor.string()?;
+  let response = match cursor.i32()? {
+    -1 => Vec::new(),
+    length if length >= 0 => cursor.take(length as usize)?.to_vec(),
+    _ => {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid SASL initial response length".to_owned(),
+      })
+    }
+  };
+  cursor.finish()?;
+  Ok((mechanism, response))
+}
+
+/// Waits for the client to answer an authentication request, returning the body of its answer.
+///
+/// Clients such as psql close the connection when asked for a password they were not given, to ask the user and reconnect. That is
+/// not worth reporting so it fails with an IO error, which is never sent to the client.
+pub(super) async fn read_password_message(stream: &mut FramedStream) -> Result<Vec<u8>, Errors> {
+  match stream.read_message().await? {
+    Some(FrontEndFrames::PasswordMessage { data }) => Ok(data),
+    Some(_) => Err(Errors::ProtocolViolation {
+      message: "expected password response".to_owned(),
+    }),
+    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+  }
+}
+
+/// Reads the null terminated password of a PasswordMessage.
+fn password(data: Vec<u8>) -> Result<String, Errors> {
+  let mut cursor = Cursor::new(&data);
+  let password = cursor.string()?;
+  cursor.finish()?;
+  Ok(password)
+}
+
+/// Whether a stored password is already hashed with MD5, as `pg_authid` keeps them.
+fn is_md5_hash(password: &str) -> bool {
+  password.len() == MD5_PREFIX.len() + 32
+    && password.starts_with(MD5_PREFIX)
+    && password[MD5_PREFIX.len()..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Hashes a password the way PostgreSQL stores it, `md5` followed by the MD5 of the password concatenated with the user name.
+fn md5_hash(password: &str, user: &str) -> String {
+  format!("{}{}", MD5_PREFIX, md5_hex(&[password.as_bytes(), user.as_bytes()].concat()))
+}
+
+/// Answer expected from the client for a password stored as an MD5 hash, `md5` followed by the MD5 of the hash concatenated with the
+/// salt.
+fn salted_md5(hash: &str, salt: &[u8; 4]) -> String {
+  format!("{}{}", MD5_PREFIX, md5_hex(&[&hash.as_bytes()[MD5_PREFIX.len()..], salt].concat()))
+}
+
+/// MD5 digest as lower case hexadecimal digits.
+fn md5_hex(data: &[u8]) -> String {
+  Md5::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{is_md5_hash, md5_hash, salted_md5};
+  use crate::errors::Errors;
+  use crate::v3::backend::BackendFrames;
+  use crate::v3::scram::Verifier;
+  use crate::v3::tests::{read_frame, read_until_ready, run_backend, startup_packet};
+  use crate::{AuthenticationType, Configuration};
+  use std::net::SocketAddr;
+  use std::time::Duration;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  /// Configuration asking for the password "secret" in clear text.
+  fn cleartext_configuration() -> Configuration {
+    password_configuration("secret", AuthenticationType::AuthenticationCleartextPassword)
+  }
+
+  /// Configuration storing a password and authenticating clients with it.
+  fn password_configuration(password: &str, authentication_type: AuthenticationType) -> Configuration {
+    let address: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+    Configuration::new("postgres".to_owned(), Some(password.to_owned()), None, address, authentication_type)
+  }
+
+  /// SASLInitialResponse choosing a mechanism.
+  fn initial_response_message(mechanism: &str, response: &[u8]) -> Vec<u8> {
+    let mut message = vec![b'p'];
+    message.extend_from_slice(&((mechanism.len() + response.len()) as i32 + 9).to_be_bytes());
+    message.extend_from_slice(mechanism.as_bytes());
+    message.push(0);
+    message.extend_from_slice(&(response.len() as i32).to_be_bytes());
+    message.extend_from_slice(response);
+    message
+  }
+
+  /// GSSResponse holding a token.
+  fn gss_response(token: &[u8]) -> Vec<u8> {
+    let mut message = vec![b'p'];
+    message.extend_from_slice(&(token.len() as i32 + 4).to_be_bytes());
+    message.extend_from_slice(token);
+    message
+  }
+
+  /// PasswordMessage holding a null terminated string.
+  fn password_message(password: &str) -> Vec<u8> {
+    let mut message = vec![b'p'];
+    message.extend_from_slice(&(password.len() as i32 + 5).to_be_bytes());
+    message.extend_from_slice(password.as_bytes());
+    message.push(0);
+    message
+  }
+
+  #[tokio::test]
+  async fn test_cleartext_password() {
+    let (_task, mut client) = run_backend(cleartext_configuration());
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationCleartextPassword);
+    client.write_all(&password_message("secret")).await.unwrap();
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_wrong_cleartext_password() {
+    let (task, mut client) = run_backend(cleartext_configuration());
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationCleartextPassword);
+    client.write_all(&password_message("wrong")).await.unwrap();
+    match read_frame(&mut client).await {
+      BackendFrames::ErrorResponse { fields } => {
+        assert!(fields.contains(&(b'S', "FATAL".to_owned())));
+        assert!(fields.contains(&(b'C', "28P01".to_owned())));
+        assert!(fields.contains(&(b'M', "password authentication failed for user \"postgres\"".to_owned())));
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+    assert!(matches!(task.await.unwrap(), Err(Errors::InvalidPassword { .. })));
+    // The connection is closed.
+    assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+    // Clients that hang up to ask the user for a password get no error.
+    let (task, mut client) = run_backend(cleartext_configuration());
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationCleartextPassword);
+    drop(client);
+    assert!(matches!(task.await.unwrap(), Err(Errors::Io { .. })));
+  }
+
+  #[test]
+  fn test_md5_hash() {
+    // SELECT 'md5' || md5('secret' || 'postgres')
+    assert_eq!(md5_hash("secret", "postgres"), "md553f48b7c4b76a86ce72276c5755f217d");
+    assert!(is_md5_hash(&md5_hash("secret", "postgres")));
+    assert!(!is_md5_hash("md5secret"));
+    assert!(!is_md5_hash("secret"));
+    assert_eq!(
+      salted_md5(&md5_hash("secret", "postgres"), &[1, 2, 3, 4]),
+      "md5bb41a296aab6baccb36ff243a562abff"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_md5_password() {
+    for stored in ["secret".to_owned(), md5_hash("secret", "postgres")] {
+      let (_task, mut client) = run_backend(password_configuration(&stored, AuthenticationType::AuthenticationMD5Password));
+      client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+      let salt = match read_frame(&mut client).await {
+        BackendFrames::AuthenticationMD5Password { salt } => salt,
+        frame => panic!("Unexpected frame {:?}", frame),
+      };
+      client
+        .write_all(&password_message(&salted_md5(&md5_hash("secret", "postgres"), &salt)))
+        .await
+        .unwrap();
+      assert_eq!(
+        read_until_ready(&mut client).await,
+        vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+      );
+    }
+
+    // The hash of the right password salted with another salt is refused.
+    let (task, mut client) = run_backend(password_configuration("secret", AuthenticationType::AuthenticationMD5Password));
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    let salt = match read_frame(&mut client).await {
+      BackendFrames::AuthenticationMD5Password { salt } => salt,
+      frame => panic!("Unexpected frame {:?}", frame),
+    };
+    let other_salt = salt.map(|byte| byte.wrapping_add(1));
+    client
+      .write_all(&password_message(&salted_md5(&md5_hash("secret", "postgres"), &other_salt)))
+      .await
+      .unwrap();
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::ErrorResponse { .. }));
+    assert!(matches!(task.await.unwrap(), Err(Errors::InvalidPassword { .. })));
+  }
+
+  #[tokio::test]
+  async fn test_cleartext_password_stored_as_md5() {
+    let (_task, mut client) = run_backend(password_configuration(
+      &md5_hash("secret", "postgres"),
+      AuthenticationType::AuthenticationCleartextPassword,
+    ));
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationCleartextPassword);
+    client.write_all(&password_message("secret")).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationOk);
+  }
+
+  #[tokio::test]
+  async fn test_sasl() {
+    let configuration = password_configuration("secret", AuthenticationType::AuthenticationSASL)
+      .with_scram_salt(b"salt".to_vec())
+      .with_scram_iterations(10)
+      .unwrap();
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(
+      read_frame(&mut client).await,
+      BackendFrames::AuthenticationSASL {
+        mechanisms: vec!["SCRAM-SHA-256".to_owned()]
+      }
+    );
+    client
+      .write_all(&initial_response_message("SCRAM-SHA-256", b"n,,n=,r=abc"))
+      .await
+      .unwrap();
+    match read_frame(&mut client).await {
+      BackendFrames::AuthenticationSASLContinue { data } => {
+        let data = String::from_utf8(data).unwrap();
+        assert!(data.starts_with("r=abc"));
+        assert!(data.ends_with(",s=c2FsdA==,i=10"));
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+
+    // Mechanisms other than the one offered are refused.
+    let (task, mut client) = run_backend(password_configuration("secret", AuthenticationType::AuthenticationSASL));
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::AuthenticationSASL { .. }));
+    client.write_all(&initial_response_message("SCRAM-SHA-1", b"n,,n=,r=abc")).await.unwrap();
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::ErrorResponse { .. }));
+    assert!(matches!(task.await.unwrap(), Err(Errors::ProtocolViolation { .. })));
+  }
+
+  #[tokio::test]
+  async fn test_md5_password_stored_as_scram() {
+    let verifier = Verifier::new("secret", b"salt", 10).to_string();
+    let (_task, mut client) = run_backend(password_configuration(&verifier, AuthenticationType::AuthenticationMD5Password));
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::AuthenticationSASL { .. }));
+
+    let (_task, mut client) = run_backend(password_configuration(&verifier, AuthenticationType::AuthenticationCleartextPassword));
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationCleartextPassword);
+    client.write_all(&password_message("secret")).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationOk);
+  }
+
+  #[tokio::test]
+  async fn test_gss() {
+    let tokens = vec![(b"first".to_vec(), b"challenge".to_vec()), (b"second".to_vec(), Vec::new())];
+    let configuration = password_configuration("secret", AuthenticationType::AuthenticationGSS { tokens });
+    let (_task, mut client) = run_backend(configuration.clone());
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationGSS);
+    client.write_all(&gss_response(b"first")).await.unwrap();
+    assert_eq!(
+      read_frame(&mut client).await,
+      BackendFrames::AuthenticationGSSContinue { data: b"challenge".to_vec() }
+    );
+    // The last token has no answer, so the negotiation ends with AuthenticationOk.
+    client.write_all(&gss_response(b"second")).await.unwrap();
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+
+    // A token other than the one expected fails authentication.
+    let (task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationGSS);
+    client.write_all(&gss_response(b"first")).await.unwrap();
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::AuthenticationGSSContinue { .. }));
+    client.write_all(&gss_response(b"wrong")).await.unwrap();
+    match read_frame(&mut client).await {
+      BackendFrames::ErrorResponse { fields } => {
+        assert!(fields.contains(&(b'C', "28000".to_owned())));
+        assert!(fields.contains(&(b'M', "GSSAPI authentication failed for user \"postgres\"".to_owned())));
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+    assert!(matches!(task.await.unwrap(), Err(Errors::InvalidAuthorizationSpecification { .. })));
+
+    // An empty answer is still sent for a token other than the last.
+    let tokens = vec![(b"first".to_vec(), Vec::new()), (b"second".to_vec(), Vec::new())];
+    let configuration = password_configuration("secret", AuthenticationType::AuthenticationGSS { tokens });
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationGSS);
+    client.write_all(&gss_response(b"first")).await.unwrap();
+    assert_eq!(
+      read_frame(&mut client).await,
+      BackendFrames::AuthenticationGSSContinue { data: Vec::new() }
+    );
+    client.write_all(&gss_response(b"second")).await.unwrap();
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+
+    // An empty script still waits for the first token before accepting the client.
+    let configuration = password_configuration("secret", AuthenticationType::AuthenticationGSS { tokens: Vec::new() });
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(read_frame(&mut client).await, BackendFrames::AuthenticationGSS);
+    assert!(tokio::time::timeout(Duration::from_millis(50), read_frame(&mut client)).await.is_err());
+    client.write_all(&gss_response(b"anything")).await.unwrap();
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+  }
+}
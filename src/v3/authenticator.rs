@@ -0,0 +1,162 @@
+//! Lets tests decide how clients authenticate with their own code, talking to clients through Authentication* requests. The built-in
+//! authentication types are implementations of the same trait.
+//! Reference: https://www.postgresql.org/docs/14/protocol-flow.html
+
+use super::authentication::read_password_message;
+use super::backend::BackendFrames;
+use super::frontend::FramedStream;
+use crate::errors::Errors;
+use crate::Configuration;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::pin::Pin;
+
+/// Future returned by [`Authenticator::authenticate`].
+pub type AuthenticationFuture<'a> = Pin<Box<dyn Future<Output = Result<(), AuthenticationError>> + Send + 'a>>;
+
+/// Decides whether a client may connect, asking it for credentials with Authentication* requests.
+///
+/// The same authenticator serves every connection, so it can keep state across them, such as how many attempts were made. Implementations
+/// return a boxed future, usually written `Box::pin(async move { .. })`.
+pub trait Authenticator: Debug + Send + Sync {
+  /// Authenticates a client. Clients receive AuthenticationOk when it succeeds, after which the role they named must still exist and be
+  /// allowed to log in, and the error in a FATAL ErrorResponse when it fails.
+  /// * `client` - Client being authenticated, with the parameters of its startup packet.
+  fn authenticate<'a>(&'a self, client: &'a mut AuthenticationClient<'_>) -> AuthenticationFuture<'a>;
+}
+
+/// Client being authenticated, which authenticators send requests to and read responses from.
+#[derive(Debug)]
+pub struct AuthenticationClient<'a> {
+  pub(crate) stream: &'a mut FramedStream,
+  pub(crate) configuration: &'a Configuration,
+  parameters: &'a HashMap<String, String>,
+}
+
+impl<'a> AuthenticationClient<'a> {
+  /// Wraps a connection that went through the startup packet.
+  pub(crate) fn new(stream: &'a mut FramedStream, configuration: &'a Configuration, parameters: &'a HashMap<String, String>) -> Self {
+    AuthenticationClient {
+      stream,
+      configuration,
+      parameters,
+    }
+  }
+
+  /// Parameters of the startup packet, which always include `user` and `database`.
+  pub fn parameters(&self) -> &HashMap<String, String> {
+    self.parameters
+  }
+
+  /// User named in the startup packet.
+  pub fn user(&self) -> &str {
+    &self.parameters["user"]
+  }
+
+  /// Sends an authentication request.
+  /// * `request` - Request, which the client usually answers with a response.
+  pub async fn send(&mut self, request: AuthenticationRequest) -> Result<(), AuthenticationError> {
+    self.stream.send(&[BackendFrames::from(request)]).await.map_err(Errors::from)?;
+    Ok(())
+  }
+
+  /// Waits for the client to answer a request, returning the body of its PasswordMessage, SASLInitialResponse, SASLResponse or
+  /// GSSResponse as is, since they share the same message type. A password is followed by a null byte.
+  pub async fn read_response(&mut self) -> Result<Vec<u8>, AuthenticationError> {
+    Ok(read_password_message(self.stream).await?)
+  }
+}
+
+/// Authentication request sent to the client, AuthenticationOk aside as it is sent once the authenticator succeeds.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AuthenticationRequest {
+  /// The client must send the password in clear text.
+  CleartextPassword,
+  /// The client must send `md5` followed by `md5(md5(password + user) + salt)` in hexadecimal digits.
+  MD5Password {
+    /// Salt the client hashes the password with.
+    salt: [u8; 4],
+  },
+  /// The client must start a GSSAPI negotiation.
+  GSS,
+  /// Continues a GSSAPI or SSPI negotiation.
+  GSSContinue {
+    /// Token sent to the client.
+    data: Vec<u8>,
+  },
+  /// The client must start an SSPI negotiation.
+  SSPI,
+  /// The client must start a SASL negotiation using one of the mechanisms listed, in order of preference.
+  SASL {
+    /// Names of the mechanisms, such as `SCRAM-SHA-256`.
+    mechanisms: Vec<String>,
+  },
+  /// Carries a SASL challenge.
+  SASLContinue {
+    /// Challenge sent to the client.
+    data: Vec<u8>,
+  },
+  /// Carries the SASL outcome.
+  SASLFinal {
+    /// Additional data sent to the client.
+    data: Vec<u8>,
+  },
+}
+
+impl From<AuthenticationRequest> for BackendFrames {
+  fn from(request: AuthenticationRequest) -> Self {
+    match request {
+      AuthenticationRequest::CleartextPassword => BackendFrames::AuthenticationCleartextPassword,
+      AuthenticationRequest::MD5Password { salt } => BackendFrames::AuthenticationMD5Password { salt },
+      AuthenticationRequest::GSS => BackendFrames::AuthenticationGSS,
+      AuthenticationRequest::GSSContinue { data } => BackendFrames::AuthenticationGSSContinue { data },
+      AuthenticationRequest::SSPI => BackendFrames::AuthenticationSSPI,
+      AuthenticationRequest::SASL { mechanisms } => BackendFrames::AuthenticationSASL { mechanisms },
+      AuthenticationRequest::SASLContinue { data } => BackendFrames::AuthenticationSASLContinue { data },
+      AuthenticationRequest::SASLFinal { data } => BackendFrames::AuthenticationSASLFinal { data },
+    }
+  }
+}
+
+/// Reason a client is refused, reported to it in a FATAL ErrorResponse unless the connection failed.
+#[derive(Debug)]
+pub struct AuthenticationError(pub(crate) Errors);
+
+impl AuthenticationError {
+  /// Refuses the client with any SQLSTATE and message.
+  /// * `code` - SQLSTATE of five characters, such as `28000` for clients that may not connect.
+  /// * `message` - Message reported to the client.
+  pub fn new(code: String, message: String) -> Self {
+    AuthenticationError(Errors::AuthenticationFailed { code, message })
+  }
+
+  /// Refuses the client the way a wrong password does, with SQLSTATE `28P01`.
+  /// * `user` - User named in the startup packet.
+  pub fn invalid_password(user: &str) -> Self {
+    AuthenticationError(Errors::InvalidPassword { user: user.to_owned() })
+  }
+
+  /// SQLSTATE reported to the client.
+  pub fn code(&self) -> &str {
+    self.0.code()
+  }
+}
+
+impl Display for AuthenticationError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl std::error::Error for AuthenticationError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    self.0.source()
+  }
+}
+
+impl From<Errors> for AuthenticationError {
+  fn from(error: Errors) -> Self {
+    AuthenticationError(error)
+  }
+}
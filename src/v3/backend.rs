@@ -0,0 +1,665 @@
+//! Holds the messages a Backend sends to the client.
+//!
+//! Every backend message has a 1 byte type, a 4 byte length that includes itself and a body.
+//! Reference: https://www.postgresql.org/docs/14/protocol-message-formats.html
+
+use super::frontend::{Cursor, FramedStream};
+use crate::errors::Errors;
+use crate::stream::slice_to_array;
+use tokio::io;
+
+/// Describes a single column of a RowDescription.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct FieldDescription {
+  pub(crate) name: String,
+  /// Object id of the table the column belongs to or zero.
+  pub(crate) table_oid: u32,
+  /// Attribute number of the column in its table or zero.
+  pub(crate) column: i16,
+  pub(crate) type_oid: u32,
+  /// Negative values denote variable-width types.
+  pub(crate) type_size: i16,
+  pub(crate) type_modifier: i32,
+  /// Zero for text, one for binary.
+  pub(crate) format: i16,
+}
+
+/// Represents packets sent to the client.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum BackendFrames {
+  /// Authentication was successful.
+  AuthenticationOk,
+  /// Kerberos V5 authentication, no longer supported by PostgreSQL.
+  AuthenticationKerberosV5,
+  /// The client must send the password in clear text.
+  AuthenticationCleartextPassword,
+  /// The client must send `md5(md5(password + user) + salt)`.
+  AuthenticationMD5Password { salt: [u8; 4] },
+  /// The client must start a GSSAPI negotiation.
+  AuthenticationGSS,
+  /// Continues a GSSAPI or SSPI negotiation.
+  AuthenticationGSSContinue { data: Vec<u8> },
+  /// The client must start an SSPI negotiation.
+  AuthenticationSSPI,
+  /// The client must start a SASL negotiation using one of the mechanisms listed, in order of preference.
+  AuthenticationSASL { mechanisms: Vec<String> },
+  /// Carries a SASL challenge.
+  AuthenticationSASLContinue { data: Vec<u8> },
+  /// Carries the SASL outcome, sent before AuthenticationOk.
+  AuthenticationSASLFinal { data: Vec<u8> },
+  /// Reports the value of a run-time parameter.
+  ParameterStatus { name: String, value: String },
+  /// Identifies the backend so the client can later cancel queries. Protocol 3.0 uses 4 byte secrets while newer versions allow up to 256 bytes.
+  BackendKeyData { process_id: u32, secret_key: Vec<u8> },
+  /// The backend is ready for a new query cycle. Status is 'I' when idle, 'T' inside a transaction and 'E' inside a failed transaction.
+  ReadyForQuery { status: u8 },
+  /// Describes the columns of the rows about to be returned.
+  RowDescription { fields: Vec<FieldDescription> },
+  /// A single row, where a column without a value is a SQL NULL.
+  DataRow { values: Vec<Option<Vec<u8>>> },
+  /// A command finished, the tag is usually the command name followed by the number of rows affected.
+  CommandComplete { tag: String },
+  /// Response to an empty query string.
+  EmptyQueryResponse,
+  /// Error made of fields identified by a single byte, such as 'S' for severity, 'C' for SQLSTATE and 'M' for message.
+  ErrorResponse { fields: Vec<(u8, String)> },
+  /// Warning with the same layout as ErrorResponse.
+  NoticeResponse { fields: Vec<(u8, String)> },
+  /// Parse finished.
+  ParseComplete,
+  /// Bind finished.
+  BindComplete,
+  /// Close finished.
+  CloseComplete,
+  /// The statement or portal described returns no rows.
+  NoData,
+  /// Object ids of the parameters of a prepared statement.
+  ParameterDescription { types: Vec<u32> },
+  /// Execute reached its row limit before the portal was exhausted.
+  PortalSuspended,
+  /// The backend is ready to receive COPY FROM STDIN data. Format is 0 for text and 1 for binary.
+  CopyInResponse { format: i8, column_formats: Vec<i16> },
+  /// The backend is about to send COPY TO STDOUT data.
+  CopyOutResponse { format: i8, column_formats: Vec<i16> },
+  /// Starts a bidirectional copy, used only by streaming replication.
+  CopyBothResponse { format: i8, column_formats: Vec<i16> },
+  /// Data sent during COPY TO STDOUT.
+  CopyData { data: Vec<u8> },
+  /// End of COPY TO STDOUT.
+  CopyDone,
+  /// Asynchronous notification raised by NOTIFY.
+  NotificationResponse { process_id: u32, channel: String, payload: String },
+  /// Result of a fast-path function call.
+  FunctionCallResponse { value: Option<Vec<u8>> },
+  /// Newest minor protocol version the server supports and the protocol options it did not recognise.
+  NegotiateProtocolVersion { minor_version: i32, options: Vec<String> },
+}
+
+impl BackendFrames {
+  /// Builds an ErrorResponse out of an application error.
+  /// * `severity` - ERROR if the session can continue, FATAL if the backend is about to close the connection.
+  /// * `error` - Error reported to the client.
+  pub(crate) fn error(severity: &str, error: &Errors) -> Self {
+    BackendFrames::ErrorResponse {
+      fields: vec![
+        (b'S', severity.to_owned()),
+        (b'V', severity.to_owned()),
+        (b'C', error.code().to_owned()),
+        (b'M', error.to_string()),
+      ],
+    }
+  }
+
+  /// Message type sent before the length.
+  fn tag(&self) -> u8 {
+    match self {
+      BackendFrames::AuthenticationOk
+      | BackendFrames::AuthenticationKerberosV5
+      | BackendFrames::AuthenticationCleartextPassword
+      | BackendFrames::AuthenticationMD5Password { .. }
+      | BackendFrames::AuthenticationGSS
+      | BackendFrames::AuthenticationGSSContinue { .. }
+      | BackendFrames::AuthenticationSSPI
+      | BackendFrames::AuthenticationSASL { .. }
+      | BackendFrames::AuthenticationSASLContinue { .. }
+      | BackendFrames::AuthenticationSASLFinal { .. } => b'R',
+      BackendFrames::ParameterStatus { .. } => b'S',
+      BackendFrames::BackendKeyData { .. } => b'K',
+      BackendFrames::ReadyForQuery { .. } => b'Z',
+      BackendFrames::RowDescription { .. } => b'T',
+      BackendFrames::DataRow { .. } => b'D',
+      BackendFrames::CommandComplete { .. } => b'C',
+      BackendFrames::EmptyQueryResponse => b'I',
+      BackendFrames::ErrorResponse { .. } => b'E',
+      BackendFrames::NoticeResponse { .. } => b'N',
+      BackendFrames::ParseComplete => b'1',
+      BackendFrames::BindComplete => b'2',
+      BackendFrames::CloseComplete => b'3',
+      BackendFrames::NoData => b'n',
+      BackendFrames::ParameterDescription { .. } => b't',
+      BackendFrames::PortalSuspended => b's',
+      BackendFrames::CopyInResponse { .. } => b'G',
+      BackendFrames::CopyOutResponse { .. } => b'H',
+      BackendFrames::CopyBothResponse { .. } => b'W',
+      BackendFrames::CopyData { .. } => b'd',
+      BackendFrames::CopyDone => b'c',
+      BackendFrames::NotificationResponse { .. } => b'A',
+      BackendFrames::FunctionCallResponse { .. } => b'V',
+      BackendFrames::NegotiateProtocolVersion { .. } => b'v',
+    }
+  }
+
+  /// Serializes everything after the length.
+  fn body(&self) -> Vec<u8> {
+    let mut body = Vec::new();
+    match self {
+      BackendFrames::AuthenticationOk => put_i32(&mut body, 0),
+      BackendFrames::AuthenticationKerberosV5 => put_i32(&mut body, 2),
+      BackendFrames::AuthenticationCleartextPassword => put_i32(&mut body, 3),
+      BackendFrames::AuthenticationMD5Password { salt } => {
+        put_i32(&mut body, 5);
+        body.extend_from_slice(salt);
+      }
+      BackendFrames::AuthenticationGSS => put_i32(&mut body, 7),
+      BackendFrames::AuthenticationGSSContinue { data } => {
+        put_i32(&mut body, 8);
+        body.extend_from_slice(data);
+      }
+      BackendFrames::AuthenticationSSPI => put_i32(&mut body, 9),
+      BackendFrames::AuthenticationSASL { mechanisms } => {
+        put_i32(&mut body, 10);
+        for mechanism in mechanisms {
+          put_string(&mut body, mechanism);
+        }
+        body.push(0);
+      }
+      BackendFrames::AuthenticationSASLContinue { data } => {
+        put_i32(&mut body, 11);
+        body.extend_from_slice(data);
+      }
+      BackendFrames::AuthenticationSASLFinal { data } => {
+        put_i32(&mut body, 12);
+        body.extend_from_slice(data);
+      }
+      BackendFrames::ParameterStatus { name, value } => {
+        put_string(&mut body, name);
+        put_string(&mut body, value);
+      }
+      BackendFrames::BackendKeyData { process_id, secret_key } => {
+        put_i32(&mut body, *process_id as i32);
+        body.extend_from_slice(secret_key);
+      }
+      BackendFrames::ReadyForQuery { status } => body.push(*status),
+      BackendFrames::RowDescription { fields } => {
+        put_i16(&mut body, fields.len() as i16);
+        for field in fields {
+          put_string(&mut body, &field.name);
+          put_i32(&mut body, field.table_oid as i32);
+          put_i16(&mut body, field.column);
+          put_i32(&mut body, field.type_oid as i32);
+          put_i16(&mut body, field.type_size);
+          put_i32(&mut body, field.type_modifier);
+          put_i16(&mut body, field.format);
+        }
+      }
+      BackendFrames::DataRow { values } => {
+        put_i16(&mut body, values.len() as i16);
+        for value in values {
+          put_value(&mut body, value);
+        }
+      }
+      BackendFrames::CommandComplete { tag } => put_string(&mut body, tag),
+      BackendFrames::EmptyQueryResponse => {}
+      BackendFrames::ErrorResponse { fields } | BackendFrames::NoticeResponse { fields } => {
+        for (code, value) in fields {
+          body.push(*code);
+          put_string(&mut body, value);
+        }
+        body.push(0);
+      }
+      BackendFrames::ParseComplete | BackendFrames::BindComplete | BackendFrames::CloseComplete | BackendFrames::NoData => {}
+      BackendFrames::ParameterDescription { types } => {
+        put_i16(&mut body, types.len() as i16);
+        for oid in types {
+          put_i32(&mut body, *oid as i32);
+        }
+      }
+      BackendFrames::PortalSuspended => {}
+      BackendFrames::CopyInResponse { format, column_formats }
+      | BackendFrames::CopyOutResponse { format, column_formats }
+      | BackendFrames::CopyBothResponse { format, column_formats } => {
+        body.push(*format as u8);
+        put_i16(&mut body, column_formats.len() as i16);
+        for column_format in column_formats {
+          put_i16(&mut body, *column_format);
+        }
+      }
+      BackendFrames::CopyData { data } => body.extend_from_slice(data),
+      BackendFrames::CopyDone => {}
+      BackendFrames::NotificationResponse {
+        process_id,
+        channel,
+        payload,
+      } => {
+        put_i32(&mut body, *process_id as i32);
+        put_string(&mut body, channel);
+        put_string(&mut body, payload);
+      }
+      BackendFrames::FunctionCallResponse { value } => put_value(&mut body, value),
+      BackendFrames::NegotiateProtocolVersion { minor_version, options } => {
+        put_i32(&mut body, *minor_version);
+        put_i32(&mut body, options.len() as i32);
+        for option in options {
+          put_string(&mut body, option);
+        }
+      }
+    }
+    body
+  }
+}
+
+fn put_i16(buf: &mut Vec<u8>, value: i16) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, value: i32) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+  buf.extend_from_slice(value.as_bytes());
+  buf.push(0);
+}
+
+/// Values are prefixed by their length, with -1 representing NULL.
+fn put_value(buf: &mut Vec<u8>, value: &Option<Vec<u8>>) {
+  match value {
+    Some(value) => {
+      put_i32(buf, value.len() as i32);
+      buf.extend_from_slice(value);
+    }
+    None => put_i32(buf, -1),
+  }
+}
+
+impl FramedStream {
+  /// Serializes frames and sends them to the client in a single write.
+  pub(crate) async fn send(&mut self, frames: &[BackendFrames]) -> io::Result<()> {
+    let mut packet = Vec::new();
+    for frame in frames {
+      packet.extend(Vec::from(frame));
+    }
+    self.write_all(&packet).await
+  }
+}
+
+impl From<&BackendFrames> for Vec<u8> {
+  fn from(frame: &BackendFrames) -> Self {
+    let body = frame.body();
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(frame.tag());
+    put_i32(&mut packet, body.len() as i32 + 4);
+    packet.extend(body);
+    packet
+  }
+}
+
+impl TryFrom<&Vec<u8>> for BackendFrames {
+  type Error = Errors;
+  fn try_from(packet: &Vec<u8>) -> Result<Self, Self::Error> {
+    if packet.len() < 5 {
+      return Err(Errors::ProtocolViolation {
+        message: "incomplete message".to_owned(),
+      });
+    }
+    let length = i32::from_be_bytes(slice_to_array(&packet[1..5]));
+    if length < 4 || length as usize != packet.len() - 1 {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid message length".to_owned(),
+      });
+    }
+
+    let mut cursor = Cursor::new(&packet[5..]);
+    let frame = match packet[0] {
+      b'R' => match cursor.i32()? {
+        0 => BackendFrames::AuthenticationOk,
+        2 => BackendFrames::AuthenticationKerberosV5,
+        3 => BackendFrames::AuthenticationCleartextPassword,
+        5 => {
+          let mut salt = [0u8; 4];
+          salt.copy_from_slice(cursor.take(4)?);
+          BackendFrames::AuthenticationMD5Password { salt }
+        }
+        7 => BackendFrames::AuthenticationGSS,
+        8 => BackendFrames::AuthenticationGSSContinue { data: cursor.rest() },
+        9 => BackendFrames::AuthenticationSSPI,
+        10 => {
+          let mut mechanisms = Vec::new();
+          loop {
+            let mechanism = cursor.string()?;
+            if mechanism.is_empty() {
+              break;
+            }
+            mechanisms.push(mechanism);
+          }
+          BackendFrames::AuthenticationSASL { mechanisms }
+        }
+        11 => BackendFrames::AuthenticationSASLContinue { data: cursor.rest() },
+        12 => BackendFrames::AuthenticationSASLFinal { data: cursor.rest() },
+        code => {
+          return Err(Errors::ProtocolViolation {
+            message: format!("invalid authentication request code {}", code),
+          })
+        }
+      },
+      b'S' => BackendFrames::ParameterStatus {
+        name: cursor.string()?,
+        value: cursor.string()?,
+      },
+      b'K' => BackendFrames::BackendKeyData {
+        process_id: cursor.i32()? as u32,
+        secret_key: cursor.rest(),
+      },
+      b'Z' => BackendFrames::ReadyForQuery { status: cursor.kind()? },
+      b'T' => {
+        let count = cursor.count()?;
+        let mut fields = Vec::with_capacity(count);
+        for _ in 0..count {
+          fields.push(FieldDescription {
+            name: cursor.string()?,
+            table_oid: cursor.i32()? as u32,
+            column: cursor.i16()?,
+            type_oid: cursor.i32()? as u32,
+            type_size: cursor.i16()?,
+            type_modifier: cursor.i32()?,
+            format: cursor.i16()?,
+          });
+        }
+        BackendFrames::RowDescription { fields }
+      }
+      b'D' => BackendFrames::DataRow { values: cursor.values()? },
+      b'C' => BackendFrames::CommandComplete { tag: cursor.string()? },
+      b'I' => BackendFrames::EmptyQueryResponse,
+      b'E' | b'N' => {
+        let mut fields = Vec::new();
+        loop {
+          let code = cursor.kind()?;
+          if code == 0 {
+            break;
+          }
+          fields.push((code, cursor.string()?));
+        }
+        if packet[0] == b'E' {
+          BackendFrames::ErrorResponse { fields }
+        } else {
+          BackendFrames::NoticeResponse { fields }
+        }
+      }
+      b'1' => BackendFrames::ParseComplete,
+      b'2' => BackendFrames::BindComplete,
+      b'3' => BackendFrames::CloseComplete,
+      b'n' => BackendFrames::NoData,
+      b't' => {
+        let count = cursor.count()?;
+        let mut types = Vec::with_capacity(count);
+        for _ in 0..count {
+          types.push(cursor.i32()? as u32);
+        }
+        BackendFrames::ParameterDescription { types }
+      }
+      b's' => BackendFrames::PortalSuspended,
+      tag @ (b'G' | b'H' | b'W') => {
+        let format = cursor.kind()? as i8;
+        let column_formats = cursor.formats()?;
+        match tag {
+          b'G' => BackendFrames::CopyInResponse { format, column_formats },
+          b'H' => BackendFrames::CopyOutResponse { format, column_formats },
+          _ => BackendFrames::CopyBothResponse { format, column_formats },
+        }
+      }
+      b'd' => BackendFrames::CopyData { data: cursor.rest() },
+      b'c' => BackendFrames::CopyDone,
+      b'A' => BackendFrames::NotificationResponse {
+        process_id: cursor.i32()? as u32,
+        channel: cursor.string()?,
+        payload: cursor.string()?,
+      },
+      b'V' => {
+        let len = cursor.i32()?;
+        let value = if len < 0 { None } else { Some(cursor.take(len as usize)?.to_vec()) };
+        BackendFrames::FunctionCallResponse { value }
+      }
+      b'v' => {
+        let minor_version = cursor.i32()?;
+        let count = cursor.i32()?;
+        let mut options = Vec::new();
+        for _ in 0..count.max(0) {
+          options.push(cursor.string()?);
+        }
+        BackendFrames::NegotiateProtocolVersion { minor_version, options }
+      }
+      tag => {
+        return Err(Errors::ProtocolViolation {
+          message: format!("invalid backend message type {}", tag),
+        })
+      }
+    };
+    cursor.finish()?;
+    Ok(frame)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{BackendFrames, FieldDescription};
+  use crate::errors::Errors;
+
+  /// Checks that a frame serializes to the expected bytes and that those bytes parse back into the same frame.
+  fn round_trip(frame: BackendFrames, packet: Vec<u8>) {
+    assert_eq!(Vec::from(&frame), packet, "{:?}", frame);
+    assert_eq!(BackendFrames::try_from(&packet).unwrap(), frame);
+  }
+
+  #[test]
+  fn test_authentication_round_trip() {
+    round_trip(
+      BackendFrames::AuthenticationOk,
+      vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00],
+    );
+    round_trip(
+      BackendFrames::AuthenticationKerberosV5,
+      vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x02],
+    );
+    round_trip(
+      BackendFrames::AuthenticationCleartextPassword,
+      vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x03],
+    );
+    round_trip(
+      BackendFrames::AuthenticationMD5Password {
+        salt: [0x01, 0x02, 0x03, 0x04],
+      },
+      vec![0x52, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04],
+    );
+    round_trip(
+      BackendFrames::AuthenticationGSS,
+      vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x07],
+    );
+    round_trip(
+      BackendFrames::AuthenticationGSSContinue { data: vec![0xaa, 0xbb] },
+      vec![0x52, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x08, 0xaa, 0xbb],
+    );
+    round_trip(
+      BackendFrames::AuthenticationSSPI,
+      vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x09],
+    );
+    // R....SCRAM-SHA-256..
+    round_trip(
+      BackendFrames::AuthenticationSASL {
+        mechanisms: vec!["SCRAM-SHA-256".to_owned()],
+      },
+      vec![
+        0x52, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0a, 0x53, 0x43, 0x52, 0x41, 0x4d, 0x2d, 0x53, 0x48, 0x41, 0x2d, 0x32, 0x35, 0x36, 0x00,
+        0x00,
+      ],
+    );
+    round_trip(
+      BackendFrames::AuthenticationSASLContinue { data: b"r=abc".to_vec() },
+      vec![0x52, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x0b, 0x72, 0x3d, 0x61, 0x62, 0x63],
+    );
+    round_trip(
+      BackendFrames::AuthenticationSASLFinal { data: b"v=xyz".to_vec() },
+      vec![0x52, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x0c, 0x76, 0x3d, 0x78, 0x79, 0x7a],
+    );
+
+    // Unknown authentication request.
+    let result = BackendFrames::try_from(&vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x04]);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+  }
+
+  #[test]
+  fn test_session_round_trip() {
+    // S....TimeZone.UTC.
+    round_trip(
+      BackendFrames::ParameterStatus {
+        name: "TimeZone".to_owned(),
+        value: "UTC".to_owned(),
+      },
+      vec![
+        0x53, 0x00, 0x00, 0x00, 0x11, 0x54, 0x69, 0x6d, 0x65, 0x5a, 0x6f, 0x6e, 0x65, 0x00, 0x55, 0x54, 0x43, 0x00,
+      ],
+    );
+    round_trip(
+      BackendFrames::BackendKeyData {
+        process_id: 1234,
+        secret_key: vec![0xde, 0xad, 0xbe, 0xef],
+      },
+      vec![0x4b, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x04, 0xd2, 0xde, 0xad, 0xbe, 0xef],
+    );
+    round_trip(BackendFrames::ReadyForQuery { status: b'I' }, vec![0x5a, 0x00, 0x00, 0x00, 0x05, 0x49]);
+    // A....chan.hi.
+    round_trip(
+      BackendFrames::NotificationResponse {
+        process_id: 7,
+        channel: "chan".to_owned(),
+        payload: "hi".to_owned(),
+      },
+      vec![
+        0x41, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x07, 0x63, 0x68, 0x61, 0x6e, 0x00, 0x68, 0x69, 0x00,
+      ],
+    );
+    // v....._pq_.x.
+    round_trip(
+      BackendFrames::NegotiateProtocolVersion {
+        minor_version: 0,
+        options: vec!["_pq_.x".to_owned()],
+      },
+      vec![
+        0x76, 0x00, 0x00, 0x00, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x5f, 0x70, 0x71, 0x5f, 0x2e, 0x78, 0x00,
+      ],
+    );
+    // E....SERROR.C28P01.Mbad..
+    round_trip(
+      BackendFrames::ErrorResponse {
+        fields: vec![(b'S', "ERROR".to_owned()), (b'C', "28P01".to_owned()), (b'M', "bad".to_owned())],
+      },
+      vec![
+        0x45, 0x00, 0x00, 0x00, 0x18, 0x53, 0x45, 0x52, 0x52, 0x4f, 0x52, 0x00, 0x43, 0x32, 0x38, 0x50, 0x30, 0x31, 0x00, 0x4d, 0x62, 0x61, 0x64,
+        0x00, 0x00,
+      ],
+    );
+    // N....SWARNING..
+    round_trip(
+      BackendFrames::NoticeResponse {
+        fields: vec![(b'S', "WARNING".to_owned())],
+      },
+      vec![0x4e, 0x00, 0x00, 0x00, 0x0e, 0x53, 0x57, 0x41, 0x52, 0x4e, 0x49, 0x4e, 0x47, 0x00, 0x00],
+    );
+  }
+
+  #[test]
+  fn test_query_round_trip() {
+    // T.....?column?.....................
+    round_trip(
+      BackendFrames::RowDescription {
+        fields: vec![FieldDescription {
+          name: "?column?".to_owned(),
+          table_oid: 0,
+          column: 0,
+          type_oid: 23,
+          type_size: 4,
+          type_modifier: -1,
+          format: 0,
+        }],
+      },
+      vec![
+        0x54, 0x00, 0x00, 0x00, 0x21, 0x00, 0x01, 0x3f, 0x63, 0x6f, 0x6c, 0x75, 0x6d, 0x6e, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x17, 0x00, 0x04, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00,
+      ],
+    );
+    round_trip(
+      BackendFrames::DataRow {
+        values: vec![Some(b"1".to_vec()), None],
+      },
+      vec![
+        0x44, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x31, 0xff, 0xff, 0xff, 0xff,
+      ],
+    );
+    round_trip(
+      BackendFrames::CommandComplete { tag: "SELECT 1".to_owned() },
+      vec![0x43, 0x00, 0x00, 0x00, 0x0d, 0x53, 0x45, 0x4c, 0x45, 0x43, 0x54, 0x20, 0x31, 0x00],
+    );
+    round_trip(BackendFrames::EmptyQueryResponse, vec![0x49, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(BackendFrames::ParseComplete, vec![0x31, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(BackendFrames::BindComplete, vec![0x32, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(BackendFrames::CloseComplete, vec![0x33, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(BackendFrames::NoData, vec![0x6e, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(
+      BackendFrames::ParameterDescription { types: vec![23, 25] },
+      vec![0x74, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x02, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x19],
+    );
+    round_trip(BackendFrames::PortalSuspended, vec![0x73, 0x00, 0x00, 0x00, 0x04]);
+    round_trip(
+      BackendFrames::FunctionCallResponse { value: Some(b"t".to_vec()) },
+      vec![0x56, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x01, 0x74],
+    );
+    round_trip(
+      BackendFrames::FunctionCallResponse { value: None },
+      vec![0x56, 0x00, 0x00, 0x00, 0x08, 0xff, 0xff, 0xff, 0xff],
+    );
+
+    // Declared length different from real length.
+    let result = BackendFrames::try_from(&vec![0x49, 0x00, 0x00, 0x00, 0x05]);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+  }
+
+  #[test]
+  fn test_copy_round_trip() {
+    round_trip(
+      BackendFrames::CopyInResponse {
+        format: 0,
+        column_formats: vec![0, 0],
+      },
+      vec![0x47, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00],
+    );
+    round_trip(
+      BackendFrames::CopyOutResponse {
+        format: 1,
+        column_formats: vec![1],
+      },
+      vec![0x48, 0x00, 0x00, 0x00, 0x09, 0x01, 0x00, 0x01, 0x00, 0x01],
+    );
+    round_trip(
+      BackendFrames::CopyBothResponse {
+        format: 0,
+        column_formats: vec![],
+      },
+      vec![0x57, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00],
+    );
+    round_trip(
+      BackendFrames::CopyData { data: b"1\n".to_vec() },
+      vec![0x64, 0x00, 0x00, 0x00, 0x06, 0x31, 0x0a],
+    );
+    round_trip(BackendFrames::CopyDone, vec![0x63, 0x00, 0x00, 0x00, 0x04]);
+  }
+}
@@ -0,0 +1,521 @@
+//! Holds the messages a client sends to a Backend and the code necessary to read them off the wire.
+//!
+//! Except for the startup packet every frontend message has a 1 byte type, a 4 byte length that includes itself and a body.
+//! Reference: https://www.postgresql.org/docs/14/protocol-message-formats.html
+
+use crate::errors::Errors;
+use crate::stream::{slice_to_array, Stream};
+use crate::ProtocolVersion;
+use std::collections::HashMap;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+/// PostgreSQL refuses messages bigger than 1GB, see PQ_LARGE_MESSAGE_LIMIT.
+static MAXIMUM_MESSAGE_LENGTH: usize = 0x3fffffff;
+static READ_CHUNK_LENGTH: usize = 8192;
+
+/// Represents packets received from the client.
+#[derive(Debug, PartialEq)]
+pub(crate) enum FrontEndFrames {
+  /// First message sent by the client when connection is opened. The only mandatory parameter is 'user' and an unknown number of parameters may be
+  /// sent so we store everything in a HashMap. Parameters named `_pq_.*` are protocol options rather than run-time parameters and are
+  /// kept apart, in the order they were sent.
+  StartupMessage {
+    protocol_version: ProtocolVersion,
+    parameters: HashMap<String, String>,
+    options: Vec<(String, String)>,
+  },
+  /// Sent instead of the startup message to ask for an encrypted connection.
+  SSLRequest,
+  /// Sent instead of the startup message to ask for a connection encrypted with GSSAPI.
+  GSSENCRequest,
+  /// Sent instead of the startup message, on a new connection, to cancel the query a backend is running.
+  CancelRequest { process_id: u32, secret_key: Vec<u8> },
+  /// Simple query.
+  Query { query: String },
+  /// Creates a prepared statement. Parameter types are object ids where 0 leaves the type unspecified.
+  Parse {
+    name: String,
+    query: String,
+    parameter_types: Vec<u32>,
+  },
+  /// Binds parameters to a prepared statement, creating a portal. A parameter without a value is a SQL NULL.
+  Bind {
+    portal: String,
+    statement: String,
+    parameter_formats: Vec<i16>,
+    parameters: Vec<Option<Vec<u8>>>,
+    result_formats: Vec<i16>,
+  },
+  /// Asks for the description of a prepared statement ('S') or a portal ('P').
+  Describe { kind: u8, name: String },
+  /// Runs a portal, returning at most `max_rows` rows or all of them if zero.
+  Execute { portal: String, max_rows: i32 },
+  /// Ends an extended query cycle.
+  Sync,
+  /// Asks the backend to deliver any pending output.
+  Flush,
+  /// Closes a prepared statement ('S') or a portal ('P').
+  Close { kind: u8, name: String },
+  /// Graceful end of the session.
+  Terminate,
+  /// Data sent during COPY FROM STDIN.
+  CopyData { data: Vec<u8> },
+  /// Successful end of COPY FROM STDIN.
+  CopyDone,
+  /// Failed end of COPY FROM STDIN.
+  CopyFail { message: String },
+  /// Calls a function through the legacy fast-path interface.
+  FunctionCall {
+    function: u32,
+    argument_formats: Vec<i16>,
+    arguments: Vec<Option<Vec<u8>>>,
+    result_format: i16,
+  },
+  /// Password, SASL and GSSAPI responses share the same type so the body is kept as is and interpreted by the authentication code.
+  PasswordMessage { data: Vec<u8> },
+}
+
+impl FrontEndFrames {
+  /// Parses the body of a typed message.
+  /// * `tag` - Message type.
+  /// * `body` - Message contents, without the type and length.
+  pub(crate) fn from_message(tag: u8, body: &[u8]) -> Result<Self, Errors> {
+    let mut cursor = Cursor::new(body);
+    let frame = match tag {
+      b'Q' => FrontEndFrames::Query { query: cursor.string()? },
+      b'P' => {
+        let name = cursor.string()?;
+        let query = cursor.string()?;
+        let count = cursor.count()?;
+        let mut parameter_types = Vec::with_capacity(count);
+        for _ in 0..count {
+          parameter_types.push(cursor.i32()? as u32);
+        }
+        FrontEndFrames::Parse {
+          name,
+          query,
+          parameter_types,
+        }
+      }
+      b'B' => {
+        let portal = cursor.string()?;
+        let statement = cursor.string()?;
+        let parameter_formats = cursor.formats()?;
+        let parameters = cursor.values()?;
+        let result_formats = cursor.formats()?;
+        FrontEndFrames::Bind {
+          portal,
+          statement,
+          parameter_formats,
+          parameters,
+          result_formats,
+        }
+      }
+      b'D' => FrontEndFrames::Describe {
+        kind: cursor.kind()?,
+        name: cursor.string()?,
+      },
+      b'E' => FrontEndFrames::Execute {
+        portal: cursor.string()?,
+        max_rows: cursor.i32()?,
+      },
+      b'S' => FrontEndFrames::Sync,
+      b'H' => FrontEndFrames::Flush,
+      b'C' => FrontEndFrames::Close {
+        kind: cursor.kind()?,
+        name: cursor.string()?,
+      },
+      b'X' => FrontEndFrames::Terminate,
+      b'd' => FrontEndFrames::CopyData { data: cursor.rest() },
+      b'c' => FrontEndFrames::CopyDone,
+      b'f' => FrontEndFrames::CopyFail { message: cursor.string()? },
+      b'F' => {
+        let function = cursor.i32()? as u32;
+        let argument_formats = cursor.formats()?;
+        let arguments = cursor.values()?;
+        let result_format = cursor.i16()?;
+        FrontEndFrames::FunctionCall {
+          function,
+          argument_formats,
+          arguments,
+          result_format,
+        }
+      }
+      b'p' => FrontEndFrames::PasswordMessage { data: cursor.rest() },
+      _ => {
+        return Err(Errors::ProtocolViolation {
+          message: format!("invalid frontend message type {}", tag),
+        })
+      }
+    };
+    cursor.finish()?;
+    Ok(frame)
+  }
+}
+
+/// Walks the body of a message, failing with a protocol violation if it is shorter than its contents claim.
+pub(super) struct Cursor<'a> {
+  body: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Cursor<'a> {
+  pub(super) fn new(body: &'a [u8]) -> Self {
+    Cursor { body, position: 0 }
+  }
+
+  /// Whether every byte of the message was read.
+  pub(super) fn is_empty(&self) -> bool {
+    self.position == self.body.len()
+  }
+
+  /// Fails if the message has bytes its contents did not account for.
+  pub(super) fn finish(&self) -> Result<(), Errors> {
+    if self.position != self.body.len() {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid message format".to_owned(),
+      });
+    }
+    Ok(())
+  }
+
+  pub(super) fn take(&mut self, len: usize) -> Result<&'a [u8], Errors> {
+    if self.body.len() - self.position < len {
+      return Err(Errors::ProtocolViolation {
+        message: "insufficient data left in message".to_owned(),
+      });
+    }
+    let bytes = &self.body[self.position..self.position + len];
+    self.position += len;
+    Ok(bytes)
+  }
+
+  pub(super) fn rest(&mut self) -> Vec<u8> {
+    let bytes = self.body[self.position..].to_vec();
+    self.position = self.body.len();
+    bytes
+  }
+
+  pub(super) fn kind(&mut self) -> Result<u8, Errors> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub(super) fn i16(&mut self) -> Result<i16, Errors> {
+    let bytes = self.take(2)?;
+    Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  pub(super) fn i32(&mut self) -> Result<i32, Errors> {
+    Ok(i32::from_be_bytes(slice_to_array(self.take(4)?)))
+  }
+
+  /// Number of elements in an array, which is always sent as an Int16.
+  pub(super) fn count(&mut self) -> Result<usize, Errors> {
+    let count = self.i16()?;
+    if count < 0 {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid message format".to_owned(),
+      });
+    }
+    Ok(count as usize)
+  }
+
+  pub(super) fn string(&mut self) -> Result<String, Errors> {
+    let terminator = self.body[self.position..]
+      .iter()
+      .position(|&x| x == 0)
+      .ok_or_else(|| Errors::ProtocolViolation {
+        message: "invalid string in message".to_owned(),
+      })?;
+    let bytes = self.take(terminator)?;
+    self.position += 1;
+    String::from_utf8(bytes.to_vec()).map_err(|_| Errors::ProtocolViolation {
+      message: "invalid byte sequence for encoding \"UTF8\"".to_owned(),
+    })
+  }
+
+  pub(super) fn formats(&mut self) -> Result<Vec<i16>, Errors> {
+    let count = self.count()?;
+    let mut formats = Vec::with_capacity(count);
+    for _ in 0..count {
+      formats.push(self.i16()?);
+    }
+    Ok(formats)
+  }
+
+  /// Values are prefixed by their length, with -1 representing NULL.
+  pub(super) fn values(&mut self) -> Result<Vec<Option<Vec<u8>>>, Errors> {
+    let count = self.count()?;
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+      let len = self.i32()?;
+      if len == -1 {
+        values.push(None);
+      } else if len < 0 {
+        return Err(Errors::ProtocolViolation {
+          message: "invalid message format".to_owned(),
+        });
+      } else {
+        values.push(Some(self.take(len as usize)?.to_vec()));
+      }
+    }
+    Ok(values)
+  }
+}
+
+/// Stream that buffers what the client sends so typed messages can be read one at a time.
+///
+/// A single read may return several messages or only part of one, so whatever is left after a message is parsed stays in the buffer
+/// for the next call.
+#[derive(Debug)]
+pub(crate) struct FramedStream {
+  stream: Stream,
+  buffer: Vec<u8>,
+}
+
+impl FramedStream {
+  /// Wraps a stream that already went through the startup packet.
+  pub(crate) fn new(stream: Stream) -> Self {
+    FramedStream { stream, buffer: Vec::new() }
+  }
+
+  /// Whether the connection is encrypted.
+  pub(crate) fn is_tls(&self) -> bool {
+    self.stream.is_tls()
+  }
+
+  /// Reads the next complete message, returning `None` if the client closed the connection between messages.
+  pub(crate) async fn read_message(&mut self) -> Result<Option<FrontEndFrames>, Errors> {
+    loop {
+      if let Some(frame) = self.next_frame()? {
+        return Ok(Some(frame));
+      }
+      let mut chunk = vec![0u8; READ_CHUNK_LENGTH];
+      match self.stream.read(&mut chunk).await? {
+        0 if self.buffer.is_empty() => return Ok(None),
+        0 => {
+          return Err(Errors::ProtocolViolation {
+            message: "unexpected EOF on client connection".to_owned(),
+          })
+        }
+        n => self.buffer.extend_from_slice(&chunk[..n]),
+      }
+    }
+  }
+
+  /// Removes the first message from the buffer if it arrived in full.
+  fn next_frame(&mut self) -> Result<Option<FrontEndFrames>, Errors> {
+    if self.buffer.len() < 5 {
+      return Ok(None);
+    }
+    let tag = self.buffer[0];
+    let length = i32::from_be_bytes(slice_to_array(&self.buffer[1..5]));
+    if length < 4 || length as usize > MAXIMUM_MESSAGE_LENGTH {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid message length".to_owned(),
+      });
+    }
+    let total = 1 + length as usize;
+    if self.buffer.len() < total {
+      return Ok(None);
+    }
+    let frame: Vec<u8> = self.buffer.drain(..total).collect();
+    FrontEndFrames::from_message(tag, &frame[5..]).map(Some)
+  }
+
+  /// Sends bytes to the client, flushing them so nothing is left behind in the TLS session.
+  pub(crate) async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+    self.stream.write_all(buf).await?;
+    self.stream.flush().await
+  }
+
+  /// Stream the messages are read from.
+  pub(crate) fn get_ref(&self) -> &Stream {
+    &self.stream
+  }
+
+  /// Stream the messages are read from, mutably.
+  pub(crate) fn get_mut(&mut self) -> &mut Stream {
+    &mut self.stream
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{FramedStream, FrontEndFrames};
+  use crate::errors::Errors;
+  use crate::stream::tests::connected_pair;
+  use std::time::Duration;
+  use tokio::io::AsyncWriteExt;
+
+  /// Prefixes a body with its type and length.
+  fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = vec![tag];
+    message.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    message.extend_from_slice(body);
+    message
+  }
+
+  #[test]
+  fn test_from_message() {
+    let frame = FrontEndFrames::from_message(b'Q', b"SELECT 1\0").unwrap();
+    assert_eq!(
+      frame,
+      FrontEndFrames::Query {
+        query: "SELECT 1".to_owned()
+      }
+    );
+
+    // Parse "s1" with one int4 parameter.
+    let body = [b"s1\0SELECT $1\0".as_slice(), &[0x00, 0x01, 0x00, 0x00, 0x00, 0x17]].concat();
+    assert_eq!(
+      FrontEndFrames::from_message(b'P', &body).unwrap(),
+      FrontEndFrames::Parse {
+        name: "s1".to_owned(),
+        query: "SELECT $1".to_owned(),
+        parameter_types: vec![23],
+      }
+    );
+
+    // Bind to the unnamed portal with a text value, a NULL and a binary result column.
+    let body = [
+      b"\0s1\0".as_slice(),
+      &[0x00, 0x01, 0x00, 0x00],
+      &[0x00, 0x02, 0x00, 0x00, 0x00, 0x02, b'4', b'2', 0xff, 0xff, 0xff, 0xff],
+      &[0x00, 0x01, 0x00, 0x01],
+    ]
+    .concat();
+    assert_eq!(
+      FrontEndFrames::from_message(b'B', &body).unwrap(),
+      FrontEndFrames::Bind {
+        portal: "".to_owned(),
+        statement: "s1".to_owned(),
+        parameter_formats: vec![0],
+        parameters: vec![Some(b"42".to_vec()), None],
+        result_formats: vec![1],
+      }
+    );
+
+    assert_eq!(
+      FrontEndFrames::from_message(b'D', b"Ss1\0").unwrap(),
+      FrontEndFrames::Describe {
+        kind: b'S',
+        name: "s1".to_owned()
+      }
+    );
+    assert_eq!(
+      FrontEndFrames::from_message(b'E', &[0x00, 0x00, 0x00, 0x00, 0x0a]).unwrap(),
+      FrontEndFrames::Execute {
+        portal: "".to_owned(),
+        max_rows: 10
+      }
+    );
+    assert_eq!(FrontEndFrames::from_message(b'S', &[]).unwrap(), FrontEndFrames::Sync);
+    assert_eq!(FrontEndFrames::from_message(b'H', &[]).unwrap(), FrontEndFrames::Flush);
+    assert_eq!(
+      FrontEndFrames::from_message(b'C', b"P\0").unwrap(),
+      FrontEndFrames::Close {
+        kind: b'P',
+        name: "".to_owned()
+      }
+    );
+    assert_eq!(FrontEndFrames::from_message(b'X', &[]).unwrap(), FrontEndFrames::Terminate);
+    assert_eq!(
+      FrontEndFrames::from_message(b'd', b"1\t2\n").unwrap(),
+      FrontEndFrames::CopyData { data: b"1\t2\n".to_vec() }
+    );
+    assert_eq!(FrontEndFrames::from_message(b'c', &[]).unwrap(), FrontEndFrames::CopyDone);
+    assert_eq!(
+      FrontEndFrames::from_message(b'f', b"aborted\0").unwrap(),
+      FrontEndFrames::CopyFail {
+        message: "aborted".to_owned()
+      }
+    );
+
+    // FunctionCall of oid 1598 with no arguments and a text result.
+    let body = [0x00, 0x00, 0x06, 0x3e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(
+      FrontEndFrames::from_message(b'F', &body).unwrap(),
+      FrontEndFrames::FunctionCall {
+        function: 1598,
+        argument_formats: vec![],
+        arguments: vec![],
+        result_format: 0,
+      }
+    );
+    assert_eq!(
+      FrontEndFrames::from_message(b'p', b"secret\0").unwrap(),
+      FrontEndFrames::PasswordMessage { data: b"secret\0".to_vec() }
+    );
+
+    // Unknown message type.
+    let result = FrontEndFrames::from_message(b'?', &[]);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+
+    // String without terminator.
+    let result = FrontEndFrames::from_message(b'Q', b"SELECT 1");
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+
+    // Execute with trailing garbage.
+    let result = FrontEndFrames::from_message(b'E', &[0x00, 0x00, 0x00, 0x00, 0x0a, 0x01]);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+
+    // Bind with a value longer than the message.
+    let body = [b"\0\0".as_slice(), &[0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x09, b'1'], &[0x00, 0x00]].concat();
+    let result = FrontEndFrames::from_message(b'B', &body);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+  }
+
+  #[tokio::test]
+  async fn test_read_message() {
+    // Several messages coalesced in a single write.
+    let (stream, mut client) = connected_pair().await;
+    let mut framed = FramedStream::new(stream);
+    let bytes = [
+      message(b'P', b"\0SELECT 1\0\0\0"),
+      message(b'E', &[0x00, 0x00, 0x00, 0x00, 0x00]),
+      message(b'S', &[]),
+    ]
+    .concat();
+    client.write_all(&bytes).await.unwrap();
+    assert!(matches!(framed.read_message().await, Ok(Some(FrontEndFrames::Parse { .. }))));
+    assert!(matches!(framed.read_message().await, Ok(Some(FrontEndFrames::Execute { .. }))));
+    assert!(matches!(framed.read_message().await, Ok(Some(FrontEndFrames::Sync))));
+
+    // A message fragmented over one write per byte.
+    let bytes = message(b'Q', b"SELECT 1\0");
+    let writer = tokio::spawn(async move {
+      for byte in bytes {
+        client.write_all(&[byte]).await.unwrap();
+        client.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+      }
+      client
+    });
+    assert_eq!(
+      framed.read_message().await.unwrap(),
+      Some(FrontEndFrames::Query {
+        query: "SELECT 1".to_owned()
+      })
+    );
+
+    // Client closes the connection between messages.
+    let client = writer.await.unwrap();
+    drop(client);
+    assert!(matches!(framed.read_message().await, Ok(None)));
+
+    // Client closes the connection in the middle of a message.
+    let (stream, mut client) = connected_pair().await;
+    let mut framed = FramedStream::new(stream);
+    client.write_all(&message(b'Q', b"SELECT 1\0")[..7]).await.unwrap();
+    drop(client);
+    assert!(matches!(framed.read_message().await, Err(Errors::ProtocolViolation { .. })));
+
+    // Declared length smaller than the length field itself.
+    let (stream, mut client) = connected_pair().await;
+    let mut framed = FramedStream::new(stream);
+    client.write_all(&[b'Q', 0x00, 0x00, 0x00, 0x03]).await.unwrap();
+    assert!(matches!(framed.read_message().await, Err(Errors::ProtocolViolation { .. })));
+  }
+}
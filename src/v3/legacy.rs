@@ -0,0 +1,348 @@
+//! Serves clients that still speak version 2.0 of the wire protocol, which PostgreSQL dropped in release 14.
+//!
+//! Only the startup and the simple query cycle are implemented. Messages have no length: most are made of a type byte followed by a null
+//! terminated string, and the startup packet is made of fixed-length fields.
+//! Reference: https://www.postgresql.org/docs/9.6/protocol-message-formats.html
+
+use super::backend::BackendFrames;
+use super::frontend::FrontEndFrames;
+use super::query::Session;
+use super::startup;
+use crate::cancel::BackendKey;
+use crate::errors::Errors;
+use crate::hba::authentication_method;
+use crate::stream::Stream;
+use crate::{AuthenticationType, Configuration, ProtocolVersion};
+use std::collections::HashMap;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Length of the startup packet, whose fields have a fixed length.
+static STARTUP_PACKET_LENGTH: usize = 296;
+/// Longest query accepted, as there is no length telling the backend how much memory a message needs.
+static MAXIMUM_QUERY_LENGTH: u64 = 0x3fffffff;
+
+/// Represents packets sent to clients speaking protocol 2.0.
+#[derive(Debug, PartialEq)]
+pub(super) enum LegacyFrames {
+  /// Authentication was successful.
+  AuthenticationOk,
+  /// Identifies the backend so the client can cancel queries later.
+  BackendKeyData { process_id: u32, secret_key: Vec<u8> },
+  /// The backend is ready for a new query.
+  ReadyForQuery,
+  /// A command finished.
+  CompletedResponse { tag: String },
+  /// Response to an empty query string.
+  EmptyQueryResponse,
+  /// Error made of a single message, prefixed by its severity.
+  ErrorResponse { message: String },
+}
+
+impl From<&LegacyFrames> for Vec<u8> {
+  fn from(frame: &LegacyFrames) -> Self {
+    let mut message = Vec::new();
+    match frame {
+      LegacyFrames::AuthenticationOk => {
+        message.push(b'R');
+        message.extend_from_slice(&0i32.to_be_bytes());
+      }
+      LegacyFrames::BackendKeyData { process_id, secret_key } => {
+        message.push(b'K');
+        message.extend_from_slice(&process_id.to_be_bytes());
+        message.extend_from_slice(secret_key);
+      }
+      LegacyFrames::ReadyForQuery => message.push(b'Z'),
+      LegacyFrames::CompletedResponse { tag } => {
+        message.push(b'C');
+        message.extend_from_slice(tag.as_bytes());
+        message.push(0);
+      }
+      LegacyFrames::EmptyQueryResponse => message.extend_from_slice(&[b'I', 0]),
+      LegacyFrames::ErrorResponse { message: text } => {
+        message.push(b'E');
+        message.extend_from_slice(text.as_bytes());
+        message.push(0);
+      }
+    }
+    message
+  }
+}
+
+impl LegacyFrames {
+  /// Builds an ErrorResponse the way servers speaking protocol 2.0 formatted them.
+  fn error(severity: &str, error: &Errors) -> Self {
+    LegacyFrames::ErrorResponse {
+      message: format!("{}:  {}\n", severity, error),
+    }
+  }
+}
+
+/// Parses a startup packet of protocol 2.0, where the database, user and options are null padded fields of 64, 32 and 64 bytes.
+/// * `packet` - Whole packet, including its length and protocol version.
+pub(super) fn startup_message(packet: &[u8]) -> Result<FrontEndFrames, Errors> {
+  if packet.len() != STARTUP_PACKET_LENGTH {
+    return Err(Errors::ProtocolViolation {
+      message: "invalid length of startup packet".to_owned(),
+    });
+  }
+  let field = |start: usize, length: usize| {
+    let bytes = &packet[start..start + length];
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(length);
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+  };
+  let user = field(72, 32);
+  if user.is_empty() {
+    return Err(Errors::InvalidAuthorizationSpecification {
+      message: "no PostgreSQL user name specified in startup packet".to_owned(),
+    });
+  }
+  let mut parameters = HashMap::new();
+  let database = field(8, 64);
+  let _ = parameters.insert("database".to_owned(), if database.is_empty() { user.clone() } else { database });
+  let _ = parameters.insert("user".to_owned(), user);
+  let options = field(104, 64);
+  if !options.is_empty() {
+    let _ = parameters.insert("options".to_owned(), options);
+  }
+  Ok(FrontEndFrames::StartupMessage {
+    protocol_version: ProtocolVersion::V2_0,
+    parameters,
+    options: vec![],
+  })
+}
+
+/// Authenticates a client speaking protocol 2.0 and answers its queries until it leaves.
+/// * `stream` - Connection the startup packet was read from.
+/// * `parameters` - Parameters of the startup packet.
+/// * `configuration` - Configuration used to define the backend process.
+/// * `key` - Identity of the backend, reported to the client once authenticated.
+pub(super) async fn serve(
+  stream: Stream,
+  parameters: &HashMap<String, String>,
+  configuration: &Configuration,
+  key: &BackendKey,
+) -> Result<(), Errors> {
+  let method = authentication_method(configuration, &startup::client(&stream, parameters));
+  let mut stream = BufReader::new(stream);
+  let method = match method {
+    Ok(method) => method,
+    Err(error) => {
+      send(&mut stream, &[LegacyFrames::error("FATAL", &error)]).await?;
+      return Err(error);
+    }
+  };
+  // Custom authenticators speak protocol 3, so they are not supported either.
+  let custom = configuration.hba.is_none() && configuration.authenticator.is_some();
+  if custom || method != AuthenticationType::Trust {
+    let name = if custom { "custom" } else { method.name() };
+    let error = Errors::FeatureNotSupported {
+      message: format!("authentication method \"{}\" not supported by protocol 2.0", name),
+    };
+    send(&mut stream, &[LegacyFrames::error("FATAL", &error)]).await?;
+    return Err(error);
+  }
+  if let Err(error) = startup::log_in(configuration, key, &parameters["user"]) {
+    send(&mut stream, &[LegacyFrames::error("FATAL", &error)]).await?;
+    return Err(error);
+  }
+  let frames = [
+    LegacyFrames::AuthenticationOk,
+    LegacyFrames::BackendKeyData {
+      process_id: key.process_id(),
+      secret_key: key.secret_key().to_vec(),
+    },
+    LegacyFrames::ReadyForQuery,
+  ];
+  send(&mut stream, &frames).await?;
+
+  let mut session = Session::new();
+  loop {
+    let frame = match read_message(&mut stream).await {
+      Ok(Some(frame)) => frame,
+      Ok(None) => return Ok(()),
+      Err(error) => {
+        let _ = send(&mut stream, &[LegacyFrames::error("FATAL", &error)]).await;
+        return Err(error);
+      }
+    };
+    let frames = match session.duration(&frame) {
+      // Queries are only canceled while running, a CancelRequest arriving in between is ignored.
+      Some(duration) => tokio::select! {
+        _ = tokio::time::sleep(duration) => session.respond(frame),
+        _ = key.canceled() => Some(session.cancel(frame)),
+      },
+      None => session.respond(frame),
+    };
+    match frames {
+      Some(frames) => send(&mut stream, &frames.iter().filter_map(legacy_frame).collect::<Vec<LegacyFrames>>()).await?,
+      None => return Ok(()),
+    }
+  }
+}
+
+/// Reads the next message, returning `None` once the client terminated the session or closed the connection.
+async fn read_message(stream: &mut BufReader<Stream>) -> Result<Option<FrontEndFrames>, Errors> {
+  let tag = match stream.read_u8().await {
+    Ok(tag) => tag,
+    Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(error) => return Err(error.into()),
+  };
+  match tag {
+    b'Q' => {
+      let mut query = Vec::new();
+      let _ = stream.take(MAXIMUM_QUERY_LENGTH).read_until(0, &mut query).await?;
+      if query.pop() != Some(0) {
+        return Err(Errors::ProtocolViolation {
+          message: "invalid string in message".to_owned(),
+        });
+      }
+      Ok(Some(FrontEndFrames::Query {
+        query: String::from_utf8_lossy(&query).into_owned(),
+      }))
+    }
+    b'X' => Ok(None),
+    _ => Err(Errors::ProtocolViolation {
+      message: format!("invalid frontend message type {}", tag),
+    }),
+  }
+}
+
+/// Sends a FATAL error in the format of protocol 2.0, to a client refused before its session starts, returning the error. Errors reading
+/// from or writing to the client are not sent.
+pub(super) async fn fatal(stream: &mut Stream, error: Errors) -> Errors {
+  if !matches!(error, Errors::Io { .. }) {
+    let _ = stream.write_all(&Vec::from(&LegacyFrames::error("FATAL", &error))).await;
+    let _ = stream.flush().await;
+  }
+  error
+}
+
+/// Translates a message of protocol 3 into its counterpart in protocol 2.0, if there is one.
+fn legacy_frame(frame: &BackendFrames) -> Option<LegacyFrames> {
+  match frame {
+    BackendFrames::CommandComplete { tag } => Some(LegacyFrames::CompletedResponse { tag: tag.clone() }),
+    BackendFrames::EmptyQueryResponse => Some(LegacyFrames::EmptyQueryResponse),
+    BackendFrames::ReadyForQuery { .. } => Some(LegacyFrames::ReadyForQuery),
+    BackendFrames::ErrorResponse { fields } => {
+      let field = |code: u8| {
+        fields
+          .iter()
+          .find(|(field, _)| *field == code)
+          .map(|(_, value)| value.as_str())
+          .unwrap_or_default()
+      };
+      Some(LegacyFrames::ErrorResponse {
+        message: format!("{}:  {}\n", field(b'S'), field(b'M')),
+      })
+    }
+    _ => None,
+  }
+}
+
+/// Sends messages to the client, flushing them right away.
+async fn send(stream: &mut BufReader<Stream>, frames: &[LegacyFrames]) -> Result<(), Errors> {
+  let mut buffer = Vec::new();
+  for frame in frames {
+    buffer.extend(Vec::from(frame));
+  }
+  stream.get_mut().write_all(&buffer).await?;
+  stream.get_mut().flush().await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{startup_message, LegacyFrames};
+  use crate::errors::Errors;
+  use crate::v3::frontend::FrontEndFrames;
+  use crate::v3::tests::run_backend;
+  use crate::{AuthenticationType, Configuration, ProtocolVersion};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  /// Builds a startup packet of protocol 2.0.
+  fn legacy_startup_packet(database: &str, user: &str) -> Vec<u8> {
+    let mut packet = vec![0u8; 296];
+    packet[..4].copy_from_slice(&296i32.to_be_bytes());
+    packet[4..8].copy_from_slice(&0x00020000i32.to_be_bytes());
+    packet[8..8 + database.len()].copy_from_slice(database.as_bytes());
+    packet[72..72 + user.len()].copy_from_slice(user.as_bytes());
+    packet
+  }
+
+  #[test]
+  fn test_startup_message() {
+    match startup_message(&legacy_startup_packet("", "postgres")) {
+      Ok(FrontEndFrames::StartupMessage {
+        protocol_version,
+        parameters,
+        ..
+      }) => {
+        assert_eq!(protocol_version, ProtocolVersion::V2_0);
+        assert_eq!(parameters["user"], "postgres");
+        assert_eq!(parameters["database"], "postgres");
+      }
+      _ => panic!("Wrong enum!"),
+    }
+    // A user is mandatory.
+    assert!(matches!(
+      startup_message(&legacy_startup_packet("db", "")),
+      Err(Errors::InvalidAuthorizationSpecification { .. })
+    ));
+    assert!(matches!(startup_message(&[0u8; 8]), Err(Errors::ProtocolViolation { .. })));
+  }
+
+  #[test]
+  fn test_legacy_frames() {
+    assert_eq!(Vec::from(&LegacyFrames::AuthenticationOk), vec![b'R', 0, 0, 0, 0]);
+    assert_eq!(Vec::from(&LegacyFrames::ReadyForQuery), vec![b'Z']);
+    assert_eq!(
+      Vec::from(&LegacyFrames::CompletedResponse { tag: "BEGIN".to_owned() }),
+      b"CBEGIN\0".to_vec()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_legacy_session() {
+    // Refused unless enabled.
+    let (task, mut client) = run_backend(Configuration::default());
+    client.write_all(&legacy_startup_packet("", "postgres")).await.unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"EFATAL:  unsupported frontend protocol 2.0: server supports 3.0 to 3.0\n\0");
+    assert!(matches!(task.await.unwrap(), Err(Errors::ProtocolViolation { .. })));
+
+    // Only trust is supported, and methods are named as in pg_hba.conf.
+    let configuration = Configuration::default().with_legacy_protocol(true).with_hba("local all all md5").unwrap();
+    let (task, mut client) = run_backend(configuration);
+    client.write_all(&legacy_startup_packet("", "postgres")).await.unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"EFATAL:  authentication method \"md5\" not supported by protocol 2.0\n\0");
+    assert!(matches!(task.await.unwrap(), Err(Errors::FeatureNotSupported { .. })));
+
+    let configuration = Configuration::default()
+      .with_legacy_protocol(true)
+      .with_authenticator(AuthenticationType::Trust);
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&legacy_startup_packet("", "postgres")).await.unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"EFATAL:  authentication method \"custom\" not supported by protocol 2.0\n\0");
+
+    let (task, mut client) = run_backend(Configuration::default().with_legacy_protocol(true));
+    client.write_all(&legacy_startup_packet("", "postgres")).await.unwrap();
+    let mut response = [0u8; 15];
+    let _ = client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response[..6], &[b'R', 0, 0, 0, 0, b'K']);
+    assert_eq!(response[14], b'Z');
+
+    client.write_all(b"QBEGIN; SELECT 1\0Q\0").await.unwrap();
+    let mut response = [0u8; 21];
+    let _ = client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"CBEGIN\0CSELECT 0\0ZI\0Z");
+
+    client.write_all(b"X").await.unwrap();
+    assert!(task.await.unwrap().is_ok());
+  }
+}
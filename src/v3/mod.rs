@@ -1,78 +1,476 @@
 //! Implements a PostgreSQL server that understands version 3 of the wire protocol.
 
+mod authentication;
+mod authenticator;
+mod backend;
+mod frontend;
+mod legacy;
+mod query;
+mod scram;
 mod startup;
 
+pub use authenticator::{AuthenticationClient, AuthenticationError, AuthenticationFuture, AuthenticationRequest, Authenticator};
+
+use crate::cancel::BackendKey;
+use crate::errors::Errors;
+use crate::stream::Stream;
 use crate::{Configuration, GenericError};
-use tokio::net::TcpStream;
+use query::Session;
+use startup::Startup;
+use std::sync::Arc;
 
 /// Represents the backend process in the PostgreSQL architecture.
 #[derive(Debug)]
-pub(crate) struct Backend<'a> {
-  configuration: &'a Configuration,
-  stream: TcpStream,
-  id: u32,
+pub(crate) struct Backend {
+  configuration: Arc<Configuration>,
+  stream: Stream,
+  key: BackendKey,
 }
 
-impl<'a> Backend<'a> {
+impl Backend {
   /// Creates a backend with user defined settings.
+  /// * `configuration` - Configuration used to define the backend process, shared with the Postmaster and every other backend.
+  /// * `stream` - Stream used to write data to and receive data from.
+  /// * `key` - Process id and secret key the Postmaster registered this backend under.
+  pub(crate) fn new(configuration: Arc<Configuration>, stream: Stream, key: BackendKey) -> Result<Self, GenericError> {
+    Ok(Backend { configuration, stream, key })
+  }
+
+  /// Serves the client until it terminates the session or the connection fails.
   ///
   /// Checks if the client sent the correct version of the wire protocol, performs the authentication handshake
-  /// and either sets the server in the ready for query state or returns an error message.
-  /// * `configuration` - Configuration used to define the backend process.
-  /// * `stream` - Stream used to write data to and receive data from.
-  /// * `id` - Postmaster generated identifier for this backend.
-  pub(crate) fn new(configuration: &'a Configuration, stream: TcpStream, id: u32) -> Result<Self, GenericError> {
-    Ok(Backend { configuration, stream, id })
+  /// and sets the server in the ready for query state, answering queries until the client leaves. Connections opened to cancel the
+  /// query of another backend end right after startup.
+  pub(crate) async fn run(mut self) -> Result<(), Errors> {
+    let (mut stream, _parameters) = match startup::startup(self.stream, &self.configuration, &mut self.key).await? {
+      Startup::Session(stream, parameters) => (stream, parameters),
+      Startup::Legacy(stream, parameters) => return legacy::serve(stream, &parameters, &self.configuration, &self.key).await,
+      Startup::Cancel => return Ok(()),
+    };
+    let mut session = Session::new();
+    stream.send(&[session.ready_for_query()]).await?;
+    loop {
+      let frame = match stream.read_message().await {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return Ok(()),
+        Err(error) => return Err(startup::fatal(stream.get_mut(), error).await),
+      };
+      let frames = match session.duration(&frame) {
+        // Queries are only canceled while running, a CancelRequest arriving in between is ignored.
+        Some(duration) => tokio::select! {
+          _ = tokio::time::sleep(duration) => session.respond(frame),
+          _ = self.key.canceled() => Some(session.cancel(frame)),
+        },
+        None => session.respond(frame),
+      };
+      match frames {
+        Some(frames) => stream.send(&frames).await?,
+        None => return Ok(()),
+      }
+    }
   }
 
   /// Returns the configuration used to create the server.
-  pub(crate) fn configuration(self) -> &'a Configuration {
+  pub(crate) fn configuration(self) -> Arc<Configuration> {
     self.configuration
   }
 
   /// Return backend identifier.
   pub(crate) fn id(self) -> u32 {
-    self.id
+    self.key.process_id()
   }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 
-  use super::super::{Configuration, GenericError, IpAddr, Ipv4Addr, SocketAddr};
-  use super::{Backend, TcpStream};
+  use super::super::{Configuration, GenericError, GssEncryption, ProtocolVersion, Role};
+  use super::backend::BackendFrames;
+  use super::{Backend, Errors, Stream};
+  use crate::cancel::Registry;
+  use std::sync::Arc;
+  use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream};
+  use tokio::task::JoinHandle;
 
-  use std::sync::Once;
-  static mut LISTENER: Option<std::net::TcpListener> = None;
-  static INIT: Once = Once::new();
+  /// Builds a startup packet for protocol 3.0 out of its parameters.
+  pub(crate) fn startup_packet(parameters: &[(&str, &str)]) -> Vec<u8> {
+    versioned_startup_packet(ProtocolVersion::V3_0, parameters)
+  }
 
-  pub(crate) fn initialize() {
-    unsafe {
-      INIT.call_once(|| LISTENER = Some(std::net::TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 5432)).unwrap()));
+  /// Builds a startup packet for any protocol version out of its parameters.
+  pub(crate) fn versioned_startup_packet(version: ProtocolVersion, parameters: &[(&str, &str)]) -> Vec<u8> {
+    let mut packet = vec![0x00, 0x00, 0x00, 0x00];
+    packet.extend_from_slice(&version.major().to_be_bytes());
+    packet.extend_from_slice(&version.minor().to_be_bytes());
+    for (name, value) in parameters {
+      packet.extend_from_slice(name.as_bytes());
+      packet.push(0);
+      packet.extend_from_slice(value.as_bytes());
+      packet.push(0);
+    }
+    packet.push(0);
+    let length = packet.len() as i32;
+    packet[..4].copy_from_slice(&length.to_be_bytes());
+    packet
+  }
+
+  /// Runs a backend over an in-memory pipe, returning its task and the client side of the pipe.
+  pub(crate) fn run_backend(configuration: Configuration) -> (JoinHandle<Result<(), Errors>>, DuplexStream) {
+    run_registered_backend(configuration, &Registry::new(std::process::id()))
+  }
+
+  /// Runs a backend registered in `registry` over an in-memory pipe, returning its task and the client side of the pipe.
+  pub(crate) fn run_registered_backend(configuration: Configuration, registry: &Arc<Registry>) -> (JoinHandle<Result<(), Errors>>, DuplexStream) {
+    let (client, server) = io::duplex(64 * 1024);
+    let backend = Backend::new(Arc::new(configuration), Stream::Duplex(server), registry.register()).unwrap();
+    (tokio::spawn(backend.run()), client)
+  }
+
+  /// Reads a single backend message from the client side of a connection.
+  pub(crate) async fn read_frame<S: AsyncRead + Unpin>(client: &mut S) -> BackendFrames {
+    let mut header = [0u8; 5];
+    let _ = client.read_exact(&mut header).await.unwrap();
+    let length = i32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let mut packet = header.to_vec();
+    packet.resize(length + 1, 0);
+    let _ = client.read_exact(&mut packet[5..]).await.unwrap();
+    BackendFrames::try_from(&packet).unwrap()
+  }
+
+  /// Reads messages until ReadyForQuery, skipping the ParameterStatus and BackendKeyData messages sent after authentication.
+  pub(crate) async fn read_until_ready<S: AsyncRead + Unpin>(client: &mut S) -> Vec<BackendFrames> {
+    let mut frames = Vec::new();
+    loop {
+      let frame = read_frame(client).await;
+      let ready = matches!(frame, BackendFrames::ReadyForQuery { .. });
+      if !matches!(frame, BackendFrames::ParameterStatus { .. } | BackendFrames::BackendKeyData { .. }) {
+        frames.push(frame);
+      }
+      if ready {
+        return frames;
+      }
     }
   }
 
-  // The default configuration uses the default PostgreSQL port so if this test fails check if the server is not running in the background.
   #[tokio::test]
   async fn test_new_backend() -> Result<(), GenericError> {
-    initialize();
-    let configuration = Configuration::default();
-    let stream = TcpStream::connect(configuration.clone().hostaddr()).await?;
-    let pid = std::process::id();
-    let backend = Backend::new(&configuration, stream, pid)?;
-    assert_eq!(backend.id(), pid);
+    let registry = Registry::new(std::process::id());
+    let (_client, server) = io::duplex(1024);
+    let backend = Backend::new(Arc::new(Configuration::default()), Stream::Duplex(server), registry.register())?;
+    let (_client, server) = io::duplex(1024);
+    let other = Backend::new(Arc::new(Configuration::default()), Stream::Duplex(server), registry.register())?;
+    assert_eq!(backend.id(), std::process::id() + 1);
+    assert_eq!(other.id(), std::process::id() + 2);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_cancel_request() -> Result<(), GenericError> {
+    let registry = Registry::new(std::process::id());
+    let (task, mut client) = run_registered_backend(Configuration::default(), &registry);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    let (process_id, secret_key) = loop {
+      match read_frame(&mut client).await {
+        BackendFrames::BackendKeyData { process_id, secret_key } => break (process_id, secret_key),
+        BackendFrames::ReadyForQuery { .. } => panic!("BackendKeyData not sent"),
+        _ => continue,
+      }
+    };
+    assert_eq!(read_frame(&mut client).await, BackendFrames::ReadyForQuery { status: b'I' });
+
+    // Q....SELECT pg_sleep(60).
+    let query = b"SELECT pg_sleep(60)\0";
+    let mut message = vec![b'Q'];
+    message.extend_from_slice(&(query.len() as i32 + 4).to_be_bytes());
+    message.extend_from_slice(query);
+    client.write_all(&message).await?;
+
+    // The CancelRequest arrives on a connection of its own, which is closed without an answer.
+    let mut cancel_request = vec![0x00, 0x00, 0x00, 0x10, 0x04, 0xd2, 0x16, 0x2e];
+    cancel_request.extend_from_slice(&process_id.to_be_bytes());
+    cancel_request.extend_from_slice(&secret_key);
+    let (canceling, mut canceling_client) = run_registered_backend(Configuration::default(), &registry);
+    canceling_client.write_all(&cancel_request).await?;
+    assert!(canceling.await?.is_ok());
+
+    match read_frame(&mut client).await {
+      BackendFrames::ErrorResponse { fields } => assert!(fields.contains(&(b'C', "57014".to_owned()))),
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+    assert_eq!(read_frame(&mut client).await, BackendFrames::ReadyForQuery { status: b'I' });
+    client.write_all(&[0x58, 0x00, 0x00, 0x00, 0x04]).await?;
+    assert!(task.await?.is_ok());
     Ok(())
   }
 
-  // The default configuration uses the default PostgreSQL port so if this test fails check if the server is not running in the background.
   #[tokio::test]
   async fn test_accept_startup_packet() -> Result<(), GenericError> {
-    initialize();
-    let configuration = Configuration::default();
-    let stream = TcpStream::connect(configuration.clone().hostaddr()).await?;
-    let pid = std::process::id();
-    let backend = Backend::new(&configuration, stream, pid)?;
-    assert_eq!(backend.id(), pid);
+    let (task, mut client) = run_backend(Configuration::default());
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+
+    // Q....SELECT 1.
+    client
+      .write_all(&[0x51, 0x00, 0x00, 0x00, 0x0d, 0x53, 0x45, 0x4c, 0x45, 0x43, 0x54, 0x20, 0x31, 0x00])
+      .await?;
+    assert_eq!(
+      read_frame(&mut client).await,
+      BackendFrames::CommandComplete { tag: "SELECT 0".to_owned() }
+    );
+    assert_eq!(read_frame(&mut client).await, BackendFrames::ReadyForQuery { status: b'I' });
+
+    // X....
+    client.write_all(&[0x58, 0x00, 0x00, 0x00, 0x04]).await?;
+    assert!(task.await?.is_ok());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_refuse_ssl_request() -> Result<(), GenericError> {
+    let (_task, mut client) = run_backend(Configuration::default());
+    client.write_all(&[0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f]).await?;
+    let mut response = [0u8; 1];
+    let _ = client.read_exact(&mut response).await?;
+    assert_eq!(&response, b"N");
+
+    // The client carries on unencrypted on the same connection.
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
     Ok(())
   }
+
+  #[tokio::test]
+  async fn test_gss_encryption_request() -> Result<(), GenericError> {
+    let gss_encryption_request = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x30];
+    let ssl_request = [0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f];
+
+    // Refused by default, after which the client may still ask for TLS.
+    let (_task, mut client) = run_backend(Configuration::default());
+    let mut response = [0u8; 2];
+    client.write_all(&gss_encryption_request).await?;
+    client.write_all(&ssl_request).await?;
+    let _ = client.read_exact(&mut response).await?;
+    assert_eq!(&response, b"NN");
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    assert_eq!(
+      read_until_ready(&mut client).await,
+      vec![BackendFrames::AuthenticationOk, BackendFrames::ReadyForQuery { status: b'I' }]
+    );
+
+    // Asking twice is a protocol violation.
+    let (task, mut client) = run_backend(Configuration::default());
+    client.write_all(&gss_encryption_request).await?;
+    client.write_all(&gss_encryption_request).await?;
+    let mut response = [0u8; 1];
+    let _ = client.read_exact(&mut response).await?;
+    assert_eq!(&response, b"N");
+    assert!(matches!(read_frame(&mut client).await, BackendFrames::ErrorResponse { .. }));
+    assert!(matches!(task.await?, Err(Errors::ProtocolViolation { .. })));
+
+    // The connection is closed without an answer.
+    let (task, mut client) = run_backend(Configuration::default().with_gss_encryption(GssEncryption::Drop));
+    client.write_all(&gss_encryption_request).await?;
+    assert!(task.await?.is_err());
+    assert_eq!(client.read(&mut response).await?, 0);
+
+    // The connection is accepted and then closed.
+    let (task, mut client) = run_backend(Configuration::default().with_gss_encryption(GssEncryption::Accept));
+    client.write_all(&gss_encryption_request).await?;
+    assert!(task.await?.is_err());
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response).await?;
+    assert_eq!(&response, b"G");
+    Ok(())
+  }
+
+  /// Reads the frames sent after the startup packet up to ReadyForQuery, including ParameterStatus and BackendKeyData.
+  async fn read_startup_frames<S: AsyncRead + Unpin>(client: &mut S) -> Vec<BackendFrames> {
+    let mut frames = Vec::new();
+    loop {
+      let frame = read_frame(client).await;
+      let ready = matches!(frame, BackendFrames::ReadyForQuery { .. });
+      frames.push(frame);
+      if ready {
+        return frames;
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_protocol_versions() -> Result<(), GenericError> {
+    let secret_key_length = |frames: &[BackendFrames]| {
+      frames.iter().find_map(|frame| match frame {
+        BackendFrames::BackendKeyData { secret_key, .. } => Some(secret_key.len()),
+        _ => None,
+      })
+    };
+
+    // Clients asking for 3.2 from a 3.0 server are told to use 3.0.
+    let (_task, mut client) = run_backend(Configuration::default());
+    client
+      .write_all(&versioned_startup_packet(ProtocolVersion::V3_2, &[("user", "postgres")]))
+      .await?;
+    let frames = read_startup_frames(&mut client).await;
+    assert_eq!(
+      frames[0],
+      BackendFrames::NegotiateProtocolVersion {
+        minor_version: 0,
+        options: vec![]
+      }
+    );
+    assert_eq!(secret_key_length(&frames), Some(4));
+
+    // A 3.2 server sends longer secret keys to clients speaking 3.2 only.
+    let configuration = Configuration::default().with_protocol_versions(ProtocolVersion::V3_0, ProtocolVersion::V3_2);
+    let (_task, mut client) = run_backend(configuration.clone());
+    client
+      .write_all(&versioned_startup_packet(ProtocolVersion::V3_2, &[("user", "postgres")]))
+      .await?;
+    let frames = read_startup_frames(&mut client).await;
+    assert_eq!(frames[0], BackendFrames::AuthenticationOk);
+    assert_eq!(secret_key_length(&frames), Some(32));
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    assert_eq!(secret_key_length(&read_startup_frames(&mut client).await), Some(4));
+
+    // Versions below the minimum are refused.
+    let configuration = Configuration::default().with_protocol_versions(ProtocolVersion::V3_2, ProtocolVersion::V3_2);
+    let (task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await?;
+    match read_frame(&mut client).await {
+      BackendFrames::ErrorResponse { fields } => {
+        assert!(fields.contains(&(b'M', "unsupported frontend protocol 3.0: server supports 3.2 to 3.2".to_owned())))
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+    assert!(matches!(task.await?, Err(Errors::ProtocolViolation { .. })));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_protocol_options() -> Result<(), GenericError> {
+    let parameters = [("user", "postgres"), ("_pq_.compression", "on"), ("_pq_.test", "1")];
+
+    // Every option is unrecognized by default, the session goes on regardless.
+    let (_task, mut client) = run_backend(Configuration::default());
+    client.write_all(&startup_packet(&parameters)).await?;
+    let frames = read_startup_frames(&mut client).await;
+    assert_eq!(
+      frames[0],
+      BackendFrames::NegotiateProtocolVersion {
+        minor_version: 0,
+        options: vec!["_pq_.compression".to_owned(), "_pq_.test".to_owned()]
+      }
+    );
+    assert_eq!(frames[1], BackendFrames::AuthenticationOk);
+    // Options are not run-time parameters.
+    assert!(!frames
+      .iter()
+      .any(|frame| matches!(frame, BackendFrames::ParameterStatus { name, .. } if name.starts_with("_pq_"))));
+
+    // Only the options the server does not recognize are reported, along with the version chosen.
+    let configuration = Configuration::default().with_protocol_options(vec!["_pq_.compression".to_owned()]);
+    let (_task, mut client) = run_backend(configuration.clone());
+    client
+      .write_all(&versioned_startup_packet(ProtocolVersion::new(3, 5), &parameters))
+      .await?;
+    assert_eq!(
+      read_startup_frames(&mut client).await[0],
+      BackendFrames::NegotiateProtocolVersion {
+        minor_version: 0,
+        options: vec!["_pq_.test".to_owned()]
+      }
+    );
+
+    // Nothing is negotiated when every option is recognized.
+    let (_task, mut client) = run_backend(configuration);
+    client.write_all(&startup_packet(&parameters[..2])).await?;
+    assert_eq!(read_startup_frames(&mut client).await[0], BackendFrames::AuthenticationOk);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn test_reject_startup_packet() -> Result<(), GenericError> {
+    // Startup packet without a user.
+    let (task, mut client) = run_backend(Configuration::default());
+    client.write_all(&startup_packet(&[("database", "postgres")])).await?;
+    match read_frame(&mut client).await {
+      BackendFrames::ErrorResponse { fields } => {
+        assert!(fields.contains(&(b'S', "FATAL".to_owned())));
+        assert!(fields.contains(&(b'C', "28000".to_owned())));
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+    assert!(matches!(task.await?, Err(Errors::InvalidAuthorizationSpecification { .. })));
+    Ok(())
+  }
+
+  /// Reads the FATAL error refusing a connection, returning its SQLSTATE and message. As in PostgreSQL, roles are checked once the client
+  /// authenticated, after AuthenticationOk.
+  async fn read_fatal<S: AsyncRead + Unpin>(client: &mut S) -> (String, String) {
+    assert_eq!(read_frame(client).await, BackendFrames::AuthenticationOk);
+    match read_frame(client).await {
+      BackendFrames::ErrorResponse { fields } => {
+        assert!(fields.contains(&(b'S', "FATAL".to_owned())));
+        let field = |code: u8| fields.iter().find(|(field, _)| *field == code).unwrap().1.clone();
+        (field(b'C'), field(b'M'))
+      }
+      frame => panic!("Unexpected frame {:?}", frame),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_roles() {
+    let configuration = Configuration::default()
+      .with_role(Role::new("alice".to_owned()).with_connection_limit(1))
+      .with_role(Role::new("bob".to_owned()).with_login(false));
+
+    let (_task, mut client) = run_backend(configuration.clone());
+    client.write_all(&startup_packet(&[("user", "carol")])).await.unwrap();
+    assert_eq!(
+      read_fatal(&mut client).await,
+      ("28000".to_owned(), "role \"carol\" does not exist".to_owned())
+    );
+
+    let (_task, mut client) = run_backend(configuration.clone());
+    client.write_all(&startup_packet(&[("user", "bob")])).await.unwrap();
+    assert_eq!(
+      read_fatal(&mut client).await,
+      ("28000".to_owned(), "role \"bob\" is not permitted to log in".to_owned())
+    );
+
+    // Only one session as alice at a time, which is not a superuser.
+    let registry = Registry::new(100);
+    let (_task, mut first) = run_registered_backend(configuration.clone(), &registry);
+    first.write_all(&startup_packet(&[("user", "alice")])).await.unwrap();
+    let mut superuser = None;
+    loop {
+      match read_frame(&mut first).await {
+        BackendFrames::ParameterStatus { name, value } if name == "is_superuser" => superuser = Some(value),
+        BackendFrames::ReadyForQuery { .. } => break,
+        _ => {}
+      }
+    }
+    assert_eq!(superuser.as_deref(), Some("off"));
+    let (_task, mut second) = run_registered_backend(configuration.clone(), &registry);
+    second.write_all(&startup_packet(&[("user", "alice")])).await.unwrap();
+    assert_eq!(
+      read_fatal(&mut second).await,
+      ("53300".to_owned(), "too many connections for role \"alice\"".to_owned())
+    );
+
+    // The superuser named after the configured user is not limited.
+    let (_task, mut client) = run_registered_backend(configuration, &registry);
+    client.write_all(&startup_packet(&[("user", "postgres")])).await.unwrap();
+    assert_eq!(
+      read_until_ready(&mut client).await.last(),
+      Some(&BackendFrames::ReadyForQuery { status: b'I' })
+    );
+  }
 }
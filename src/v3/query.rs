@@ -0,0 +1,399 @@
+//! Holds the code necessary to answer queries once a Backend is ready for query.
+//!
+//! The mock has no storage so statements are acknowledged without returning rows. This is enough for clients to go through the simple and
+//! extended query cycles. Statements calling `pg_sleep` take as long as asked, giving clients a query in flight they can cancel.
+//! Reference: https://www.postgresql.org/docs/14/protocol-flow.html#PROTOCOL-FLOW-EXT-QUERY
+
+use super::backend::BackendFrames;
+use super::frontend::FrontEndFrames;
+use crate::errors::Errors;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Transaction status reported by ReadyForQuery.
+static IDLE: u8 = b'I';
+static IN_TRANSACTION: u8 = b'T';
+static FAILED_TRANSACTION: u8 = b'E';
+
+/// State kept between messages of a session.
+#[derive(Debug)]
+pub(super) struct Session {
+  /// Prepared statements by name, holding the query and its parameter types.
+  statements: HashMap<String, (String, Vec<u32>)>,
+  /// Portals by name, holding the query they run.
+  portals: HashMap<String, String>,
+  status: u8,
+  /// Whether an extended query cycle failed, in which case messages are ignored until Sync.
+  failed: bool,
+}
+
+impl Session {
+  pub(super) fn new() -> Self {
+    Session {
+      statements: HashMap::new(),
+      portals: HashMap::new(),
+      status: IDLE,
+      failed: false,
+    }
+  }
+
+  /// Frame telling the client the backend is waiting for a new query cycle.
+  pub(super) fn ready_for_query(&self) -> BackendFrames {
+    BackendFrames::ReadyForQuery { status: self.status }
+  }
+
+  /// Returns the frames answering a message or `None` if the client asked to close the session.
+  pub(super) fn respond(&mut self, frame: FrontEndFrames) -> Option<Vec<BackendFrames>> {
+    if self.failed && !matches!(frame, FrontEndFrames::Sync | FrontEndFrames::Terminate) {
+      return Some(vec![]);
+    }
+    let frames = match frame {
+      FrontEndFrames::Query { query } => {
+        let mut frames = Vec::new();
+        for statement in query.split(';').filter(|statement| !statement.trim().is_empty()) {
+          match self.execute(statement) {
+            Ok(frame) => frames.push(frame),
+            // The statements following the one that failed are skipped.
+            Err(error) => {
+              frames.push(BackendFrames::error("ERROR", &error));
+              break;
+            }
+          }
+        }
+        if frames.is_empty() {
+          frames.push(BackendFrames::EmptyQueryResponse);
+        }
+        frames.push(self.ready_for_query());
+        frames
+      }
+      FrontEndFrames::Parse {
+        name,
+        query,
+        parameter_types,
+      } => {
+        let _ = self.statements.insert(name, (query, parameter_types));
+        vec![BackendFrames::ParseComplete]
+      }
+      FrontEndFrames::Bind { portal, statement, .. } => match self.statements.get(&statement) {
+        Some((query, _)) => {
+          let _ = self.portals.insert(portal, query.clone());
+          vec![BackendFrames::BindComplete]
+        }
+        None => self.fail(Errors::InvalidSqlStatementName { name: statement }),
+      },
+      FrontEndFrames::Describe { kind: b'S', name } => match self.statements.get(&name) {
+        Some((_, types)) => vec![BackendFrames::ParameterDescription { types: types.clone() }, BackendFrames::NoData],
+        None => self.fail(Errors::InvalidSqlStatementName { name }),
+      },
+      FrontEndFrames::Describe { name, .. } if !self.portals.contains_key(&name) => self.fail(Errors::InvalidCursorName { name }),
+      FrontEndFrames::Describe { .. } => vec![BackendFrames::NoData],
+      FrontEndFrames::Execute { portal, .. } => match self.portals.get(&portal).cloned() {
+        Some(query) if query.trim().is_empty() => vec![BackendFrames::EmptyQueryResponse],
+        Some(query) => match self.execute(&query) {
+          Ok(frame) => vec![frame],
+          Err(error) => self.fail(error),
+        },
+        None => self.fail(Errors::InvalidCursorName { name: portal }),
+      },
+      FrontEndFrames::Close { kind, name } => {
+        if kind == b'S' {
+          let _ = self.statements.remove(&name);
+        } else {
+          let _ = self.portals.remove(&name);
+        }
+        vec![BackendFrames::CloseComplete]
+      }
+      FrontEndFrames::Sync => {
+        // The unnamed portal only lives until the end of the cycle.
+        let _ = self.portals.remove("");
+        self.failed = false;
+        vec![self.ready_for_query()]
+      }
+      FrontEndFrames::FunctionCall { .. } => vec![BackendFrames::FunctionCallResponse { value: None }, self.ready_for_query()],
+      FrontEndFrames::Terminate => return None,
+      // Output is never buffered and no COPY is ever in progress.
+      FrontEndFrames::Flush | FrontEndFrames::CopyData { .. } | FrontEndFrames::CopyDone | FrontEndFrames::CopyFail { .. } => vec![],
+      FrontEndFrames::StartupMessage { .. }
+      | FrontEndFrames::SSLRequest
+      | FrontEndFrames::GSSENCRequest
+      | FrontEndFrames::CancelRequest { .. }
+      | FrontEndFrames::PasswordMessage { .. } => vec![],
+    };
+    Some(frames)
+  }
+
+  /// Time the statements of a message take to run, if any of them sleeps.
+  pub(super) fn duration(&self, frame: &FrontEndFrames) -> Option<Duration> {
+    if self.failed {
+      return None;
+    }
+    let query = match frame {
+      FrontEndFrames::Query { query } => query,
+      FrontEndFrames::Execute { portal, .. } => self.portals.get(portal)?,
+      _ => return None,
+    };
+    // Statements of a failed transaction are refused without running.
+    if query
+      .split(';')
+      .find(|statement| !statement.trim().is_empty())
+      .is_some_and(|statement| self.refuses(statement))
+    {
+      return None;
+    }
+    query.split(';').filter_map(sleep_duration).reduce(|total, duration| total + duration)
+  }
+
+  /// Returns the frames answering a message whose statements were canceled while running.
+  ///
+  /// Statements of a simple query before the first one that sleeps complete, the rest are aborted. In the extended protocol the rest of the
+  /// cycle is skipped until Sync. Either way an open transaction fails.
+  pub(super) fn cancel(&mut self, frame: FrontEndFrames) -> Vec<BackendFrames> {
+    let mut frames = match frame {
+      FrontEndFrames::Query { query } => query
+        .split(';')
+        .filter(|statement| !statement.trim().is_empty())
+        .take_while(|statement| sleep_duration(statement).is_none())
+        .map_while(|statement| self.execute(statement).ok())
+        .collect(),
+      _ => {
+        self.failed = true;
+        vec![]
+      }
+    };
+    if self.status == IN_TRANSACTION {
+      self.status = FAILED_TRANSACTION;
+    }
+    frames.push(BackendFrames::error("ERROR", &Errors::QueryCanceled));
+    if !self.failed {
+      frames.push(self.ready_for_query());
+    }
+    frames
+  }
+
+  /// Reports an error that makes the server skip the rest of the extended query cycle until Sync.
+  fn fail(&mut self, error: Errors) -> Vec<BackendFrames> {
+    self.failed = true;
+    vec![BackendFrames::error("ERROR", &error)]
+  }
+
+  /// Whether a statement is refused because the transaction failed, which can then only be ended.
+  fn refuses(&self, statement: &str) -> bool {
+    let command = statement.split_whitespace().next().unwrap_or_default().to_uppercase();
+    self.status == FAILED_TRANSACTION && !matches!(command.as_str(), "COMMIT" | "END" | "ROLLBACK" | "ABORT")
+  }
+
+  /// Acknowledges a single statement, tracking transaction boundaries along the way.
+  fn execute(&mut self, statement: &str) -> Result<BackendFrames, Errors> {
+    if self.refuses(statement) {
+      return Err(Errors::InFailedSqlTransaction);
+    }
+    let words: Vec<String> = statement.split_whitespace().take(2).map(|word| word.to_uppercase()).collect();
+    let command = words.first().cloned().unwrap_or_default();
+    // Committing a failed transaction rolls it back.
+    if self.status == FAILED_TRANSACTION && (command == "COMMIT" || command == "END") {
+      self.status = IDLE;
+      return Ok(BackendFrames::CommandComplete { tag: "ROLLBACK".to_owned() });
+    }
+    match command.as_str() {
+      "BEGIN" | "START" => self.status = IN_TRANSACTION,
+      "COMMIT" | "END" | "ROLLBACK" | "ABORT" => self.status = IDLE,
+      _ => {}
+    }
+    let tag = match command.as_str() {
+      "SELECT" | "UPDATE" | "DELETE" | "MERGE" | "FETCH" | "MOVE" | "COPY" => format!("{} 0", command),
+      "INSERT" => "INSERT 0 0".to_owned(),
+      "START" => "START TRANSACTION".to_owned(),
+      "CREATE" | "DROP" | "ALTER" => words.join(" "),
+      _ => command,
+    };
+    Ok(BackendFrames::CommandComplete { tag })
+  }
+}
+
+/// Seconds a statement asks to sleep for through `pg_sleep`.
+fn sleep_duration(statement: &str) -> Option<Duration> {
+  let statement = statement.to_lowercase();
+  let start = statement.find("pg_sleep(")? + "pg_sleep(".len();
+  let length = statement[start..].find(')')?;
+  let seconds = statement[start..start + length].trim().trim_matches('\'').parse::<f64>().ok()?;
+  Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{sleep_duration, Session};
+  use crate::v3::backend::BackendFrames;
+  use crate::v3::frontend::FrontEndFrames;
+  use std::time::Duration;
+
+  #[test]
+  fn test_simple_query() {
+    let mut session = Session::new();
+    let frames = session.respond(FrontEndFrames::Query {
+      query: "BEGIN; insert into t values (1);".to_owned(),
+    });
+    assert_eq!(
+      frames,
+      Some(vec![
+        BackendFrames::CommandComplete { tag: "BEGIN".to_owned() },
+        BackendFrames::CommandComplete {
+          tag: "INSERT 0 0".to_owned()
+        },
+        BackendFrames::ReadyForQuery { status: b'T' },
+      ])
+    );
+
+    let frames = session.respond(FrontEndFrames::Query { query: " ; ".to_owned() });
+    assert_eq!(
+      frames,
+      Some(vec![BackendFrames::EmptyQueryResponse, BackendFrames::ReadyForQuery { status: b'T' }])
+    );
+
+    let frames = session.respond(FrontEndFrames::Query { query: "COMMIT".to_owned() });
+    assert_eq!(
+      frames,
+      Some(vec![
+        BackendFrames::CommandComplete { tag: "COMMIT".to_owned() },
+        BackendFrames::ReadyForQuery { status: b'I' },
+      ])
+    );
+
+    assert_eq!(session.respond(FrontEndFrames::Terminate), None);
+  }
+
+  #[test]
+  fn test_extended_query() {
+    let mut session = Session::new();
+    let frames = session.respond(FrontEndFrames::Parse {
+      name: "s1".to_owned(),
+      query: "CREATE TABLE t (id int)".to_owned(),
+      parameter_types: vec![23],
+    });
+    assert_eq!(frames, Some(vec![BackendFrames::ParseComplete]));
+
+    let frames = session.respond(FrontEndFrames::Describe {
+      kind: b'S',
+      name: "s1".to_owned(),
+    });
+    assert_eq!(
+      frames,
+      Some(vec![BackendFrames::ParameterDescription { types: vec![23] }, BackendFrames::NoData])
+    );
+
+    let frames = session.respond(FrontEndFrames::Bind {
+      portal: "".to_owned(),
+      statement: "s1".to_owned(),
+      parameter_formats: vec![],
+      parameters: vec![],
+      result_formats: vec![],
+    });
+    assert_eq!(frames, Some(vec![BackendFrames::BindComplete]));
+
+    let frames = session.respond(FrontEndFrames::Execute {
+      portal: "".to_owned(),
+      max_rows: 0,
+    });
+    assert_eq!(
+      frames,
+      Some(vec![BackendFrames::CommandComplete {
+        tag: "CREATE TABLE".to_owned()
+      }])
+    );
+
+    let frames = session.respond(FrontEndFrames::Sync);
+    assert_eq!(frames, Some(vec![BackendFrames::ReadyForQuery { status: b'I' }]));
+
+    let frames = session.respond(FrontEndFrames::Close {
+      kind: b'S',
+      name: "s1".to_owned(),
+    });
+    assert_eq!(frames, Some(vec![BackendFrames::CloseComplete]));
+
+    // Binding a statement that does not exist fails, and the rest of the cycle is skipped.
+    let frames = session.respond(FrontEndFrames::Bind {
+      portal: "".to_owned(),
+      statement: "s1".to_owned(),
+      parameter_formats: vec![],
+      parameters: vec![],
+      result_formats: vec![],
+    });
+    assert!(matches!(frames.as_deref(), Some([BackendFrames::ErrorResponse { fields }])
+      if fields.contains(&(b'C', "26000".to_owned())) && fields.contains(&(b'M', "prepared statement \"s1\" does not exist".to_owned()))));
+    let frames = session.respond(FrontEndFrames::Execute {
+      portal: "".to_owned(),
+      max_rows: 0,
+    });
+    assert_eq!(frames, Some(vec![]));
+    let frames = session.respond(FrontEndFrames::Sync);
+    assert_eq!(frames, Some(vec![BackendFrames::ReadyForQuery { status: b'I' }]));
+
+    let frames = session.respond(FrontEndFrames::Execute {
+      portal: "p1".to_owned(),
+      max_rows: 0,
+    });
+    assert!(matches!(frames.as_deref(), Some([BackendFrames::ErrorResponse { fields }])
+      if fields.contains(&(b'C', "34000".to_owned())) && fields.contains(&(b'M', "portal \"p1\" does not exist".to_owned()))));
+  }
+
+  #[test]
+  fn test_sleep_duration() {
+    assert_eq!(sleep_duration("SELECT pg_sleep(1.5)"), Some(Duration::from_millis(1500)));
+    assert_eq!(sleep_duration("select PG_SLEEP( '2' )"), Some(Duration::from_secs(2)));
+    assert_eq!(sleep_duration("SELECT pg_sleep(-1)"), None);
+    assert_eq!(sleep_duration("SELECT 1"), None);
+
+    let session = Session::new();
+    let frame = FrontEndFrames::Query {
+      query: "SELECT pg_sleep(1); SELECT 1; SELECT pg_sleep(2)".to_owned(),
+    };
+    assert_eq!(session.duration(&frame), Some(Duration::from_secs(3)));
+  }
+
+  #[test]
+  fn test_cancel() {
+    let mut session = Session::new();
+    let frames = session.cancel(FrontEndFrames::Query {
+      query: "BEGIN; SELECT pg_sleep(10); SELECT 1".to_owned(),
+    });
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0], BackendFrames::CommandComplete { tag: "BEGIN".to_owned() });
+    assert!(matches!(&frames[1], BackendFrames::ErrorResponse { fields } if fields.contains(&(b'C', "57014".to_owned()))));
+    assert_eq!(frames[2], BackendFrames::ReadyForQuery { status: b'E' });
+    // Statements of the failed transaction are refused until it ends, skipping the rest of the query.
+    let frame = FrontEndFrames::Query {
+      query: "SELECT pg_sleep(10); COMMIT".to_owned(),
+    };
+    assert_eq!(session.duration(&frame), None);
+    let frames = session.respond(frame).unwrap();
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(&frames[0], BackendFrames::ErrorResponse { fields } if fields.contains(&(b'C', "25P02".to_owned()))));
+    assert_eq!(frames[1], BackendFrames::ReadyForQuery { status: b'E' });
+    let frames = session.respond(FrontEndFrames::Query { query: "COMMIT".to_owned() });
+    assert_eq!(
+      frames,
+      Some(vec![
+        BackendFrames::CommandComplete { tag: "ROLLBACK".to_owned() },
+        BackendFrames::ReadyForQuery { status: b'I' },
+      ])
+    );
+
+    // The rest of an extended query cycle is skipped.
+    let frames = session.cancel(FrontEndFrames::Execute {
+      portal: "".to_owned(),
+      max_rows: 0,
+    });
+    assert!(matches!(&frames[..], [BackendFrames::ErrorResponse { .. }]));
+    assert_eq!(
+      session.respond(FrontEndFrames::Describe {
+        kind: b'P',
+        name: "".to_owned()
+      }),
+      Some(vec![])
+    );
+    assert_eq!(
+      session.respond(FrontEndFrames::Sync),
+      Some(vec![BackendFrames::ReadyForQuery { status: b'I' }])
+    );
+  }
+}
@@ -0,0 +1,388 @@
+//! Holds the server side of the SCRAM-SHA-256 SASL mechanism.
+//!
+//! Passwords are kept as verifiers, made of a salt, an iteration count and two keys derived from the password, so the server can check
+//! the client knows the password without either side sending it.
+//! Reference: https://www.postgresql.org/docs/14/sasl-authentication.html and RFC 5802.
+
+use crate::errors::Errors;
+use crate::random_bytes;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+
+/// Name of the mechanism, as announced in AuthenticationSASL.
+pub(super) static SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+/// Name of the mechanism binding the exchange to the TLS connection, only announced over TLS.
+pub(super) static SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+/// Only channel binding type supported, made of a hash of the server certificate.
+static TLS_SERVER_END_POINT: &str = "tls-server-end-point";
+/// Iterations used to derive keys out of a password, the default of PostgreSQL.
+pub(super) static DEFAULT_ITERATIONS: u32 = 4096;
+/// Length of the salt generated for passwords, the default of PostgreSQL.
+pub(super) static DEFAULT_SALT_LENGTH: usize = 16;
+/// Random bytes in the nonce of the server.
+static NONCE_LENGTH: usize = 18;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What the server stores about a password, written `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>` as in `pg_authid`.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) struct Verifier {
+  iterations: u32,
+  salt: Vec<u8>,
+  stored_key: Vec<u8>,
+  server_key: Vec<u8>,
+}
+
+impl Verifier {
+  /// Derives the verifier of a password.
+  /// * `password` - Password in clear text, normalized with SASLprep when possible.
+  /// * `salt` - Salt mixed with the password.
+  /// * `iterations` - Number of times the password is hashed, making guessing it slower.
+  pub(super) fn new(password: &str, salt: &[u8], iterations: u32) -> Self {
+    let salted_password = salted_password(password, salt, iterations);
+    let client_key = hmac(&salted_password, b"Client Key");
+    Verifier {
+      iterations,
+      salt: salt.to_vec(),
+      stored_key: Sha256::digest(client_key).to_vec(),
+      server_key: hmac(&salted_password, b"Server Key"),
+    }
+  }
+
+  /// Reads a verifier written as `SCRAM-SHA-256$<iterations>:<salt>$<stored key>:<server key>`, returning `None` for anything else,
+  /// such as a password in clear text.
+  pub(super) fn parse(verifier: &str) -> Option<Self> {
+    let rest = verifier.strip_prefix(SCRAM_SHA_256)?.strip_prefix('$')?;
+    let (parameters, keys) = rest.split_once('$')?;
+    let (iterations, salt) = parameters.split_once(':')?;
+    let (stored_key, server_key) = keys.split_once(':')?;
+    let verifier = Verifier {
+      iterations: iterations.parse().ok().filter(|iterations| *iterations > 0)?,
+      salt: STANDARD.decode(salt).ok()?,
+      stored_key: STANDARD.decode(stored_key).ok()?,
+      server_key: STANDARD.decode(server_key).ok()?,
+    };
+    if verifier.stored_key.len() != 32 || verifier.server_key.len() != 32 {
+      return None;
+    }
+    Some(verifier)
+  }
+
+  /// Verifier no password matches, used to go through the whole exchange before failing when the user has no usable password, as
+  /// PostgreSQL does so clients can't tell why authentication failed.
+  pub(super) fn mock() -> Self {
+    Verifier {
+      iterations: DEFAULT_ITERATIONS,
+      salt: random_bytes(DEFAULT_SALT_LENGTH),
+      stored_key: random_bytes(32),
+      server_key: random_bytes(32),
+    }
+  }
+
+  /// Whether a password in clear text is the one the verifier was derived from.
+  pub(super) fn verify_password(&self, password: &str) -> bool {
+    *self == Verifier::new(password, &self.salt, self.iterations)
+  }
+}
+
+impl Display for Verifier {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}${}:{}${}:{}",
+      SCRAM_SHA_256,
+      self.iterations,
+      STANDARD.encode(&self.salt),
+      STANDARD.encode(&self.stored_key),
+      STANDARD.encode(&self.server_key)
+    )
+  }
+}
+
+/// Channel binding as negotiated through the mechanism the client selected.
+#[derive(Debug, PartialEq, Clone)]
+pub(super) enum Binding {
+  /// The server did not offer SCRAM-SHA-256-PLUS.
+  Unsupported,
+  /// The server offered SCRAM-SHA-256-PLUS but the client selected SCRAM-SHA-256.
+  Offered,
+  /// The client selected SCRAM-SHA-256-PLUS, which binds the exchange to the data supplied.
+  Selected(Vec<u8>),
+}
+
+/// State of an exchange between the first message of the server and the last message of the client.
+#[derive(Debug)]
+pub(super) struct Exchange {
+  verifier: Verifier,
+  user: String,
+  /// GS2 header sent by the client followed by the channel binding data, which it must repeat in its final message.
+  binding: Vec<u8>,
+  client_first_bare: String,
+  server_first: String,
+  nonce: String,
+  /// Whether the server-final-message is signed as if the client had sent other binding data.
+  wrong_binding: bool,
+}
+
+impl Exchange {
+  /// Answers the client-first-message with the server-first-message, holding the nonce, salt and iteration count.
+  /// * `verifier` - Verifier of the password of the user.
+  /// * `user` - User named in the startup packet, since PostgreSQL ignores the user name of SCRAM messages.
+  /// * `channel_binding` - Channel binding negotiated through the mechanism selected.
+  /// * `client_first` - First message of the client.
+  pub(super) fn start(verifier: Verifier, user: &str, channel_binding: Binding, client_first: &[u8]) -> Result<(Self, String), Errors> {
+    let client_first = std::str::from_utf8(client_first).map_err(|_| malformed("message is not valid UTF-8"))?;
+    let (flag, rest) = client_first.split_once(',').ok_or_else(|| malformed("comma expected"))?;
+    let binding_data = match (flag, channel_binding) {
+      ("n", Binding::Unsupported | Binding::Offered) | ("y", Binding::Unsupported) => Vec::new(),
+      // The client supports channel binding yet thinks the server does not, so something in between removed SCRAM-SHA-256-PLUS.
+      ("y", Binding::Offered) => {
+        return Err(Errors::ProtocolViolation {
+          message: "SCRAM channel binding negotiation error".to_owned(),
+        })
+      }
+      ("n" | "y", Binding::Selected(_)) => {
+        return Err(malformed(
+          "The client selected SCRAM-SHA-256-PLUS, but the SCRAM message does not include channel binding data.",
+        ))
+      }
+      (flag, Binding::Selected(data)) if flag.strip_prefix("p=") == Some(TLS_SERVER_END_POINT) => data,
+      (flag, Binding::Selected(_)) if flag.starts_with("p=") => {
+        return Err(Errors::FeatureNotSupported {
+          message: format!("unsupported SCRAM channel-binding type \"{}\"", &flag[2..]),
+        })
+      }
+      (flag, _) if flag.starts_with("p=") => {
+        return Err(malformed(
+          "The client selected SCRAM-SHA-256 without channel binding, but the SCRAM message includes channel binding data.",
+        ))
+      }
+      _ => return Err(malformed("unexpected channel-binding flag")),
+    };
+    let (authorization_identity, client_first_bare) = rest.split_once(',').ok_or_else(|| malformed("comma expected"))?;
+    if !authorization_identity.is_empty() {
+      return Err(Errors::FeatureNotSupported {
+        message: "client uses authorization identity, but it is not supported".to_owned(),
+      });
+    }
+    let mut attributes = client_first_bare.split(',');
+    if !attributes.next().is_some_and(|attribute| attribute.starts_with("n=")) {
+      return Err(malformed("expected attribute \"n\""));
+    }
+    let client_nonce = attributes
+      .next()
+      .and_then(|attribute| attribute.strip_prefix("r="))
+      .filter(|nonce| !nonce.is_empty() && nonce.bytes().all(|byte| (0x21..=0x7e).contains(&byte)))
+      .ok_or_else(|| malformed("expected attribute \"r\""))?;
+    if attributes.next().is_some() {
+      return Err(malformed("garbage found at the end of client-first-message"));
+    }
+
+    let nonce = format!("{}{}", client_nonce, STANDARD.encode(random_bytes(NONCE_LENGTH)));
+    let server_first = format!("r={},s={},i={}", nonce, STANDARD.encode(&verifier.salt), verifier.iterations);
+    let exchange = Exchange {
+      verifier,
+      user: user.to_owned(),
+      binding: [format!("{},{},", flag, authorization_identity).as_bytes(), &binding_data].concat(),
+      client_first_bare: client_first_bare.to_owned(),
+      server_first: server_first.clone(),
+      nonce,
+      wrong_binding: false,
+    };
+    Ok((exchange, server_first))
+  }
+
+  /// Signs the server-final-message as a man in the middle would, with binding data other than the one the client sent, so the client
+  /// refuses the server even though the server accepted its proof.
+  pub(super) fn with_wrong_binding(mut self) -> Self {
+    self.wrong_binding = true;
+    self
+  }
+
+  /// Checks the proof in the client-final-message, answering it with the server-final-message that proves the server knows the
+  /// password too.
+  /// * `client_final` - Last message of the client.
+  pub(super) fn finish(self, client_final: &[u8]) -> Result<String, Errors> {
+    let client_final = std::str::from_utf8(client_final).map_err(|_| malformed("message is not valid UTF-8"))?;
+    let (without_proof, proof) = client_final.rsplit_once(",p=").ok_or_else(|| malformed("could not find proof"))?;
+    let mut attributes = without_proof.split(',');
+    let binding = attributes
+      .next()
+      .and_then(|attribute| attribute.strip_prefix("c="))
+      .ok_or_else(|| malformed("expected attribute \"c\""))?;
+    if STANDARD.decode(binding).ok().as_deref() != Some(self.binding.as_slice()) {
+      return Err(Errors::ProtocolViolation {
+        message: "SCRAM channel binding check failed".to_owned(),
+      });
+    }
+    if attributes.next().and_then(|attribute| attribute.strip_prefix("r=")) != Some(self.nonce.as_str()) {
+      return Err(malformed("unexpected SCRAM nonce in client-final-message"));
+    }
+    let proof = STANDARD
+      .decode(proof)
+      .ok()
+      .filter(|proof| proof.len() == 32)
+      .ok_or_else(|| malformed("malformed proof"))?;
+
+    let auth_message = format!("{},{},{}", self.client_first_bare, self.server_first, without_proof);
+    let client_signature = hmac(&self.verifier.stored_key, auth_message.as_bytes());
+    let client_key: Vec<u8> = proof.iter().zip(client_signature).map(|(proof, signature)| proof ^ signature).collect();
+    if Sha256::digest(&client_key).as_slice() != self.verifier.stored_key.as_slice() {
+      return Err(Errors::InvalidPassword { user: self.user });
+    }
+    let auth_message = if self.wrong_binding {
+      // The client checks the signature against the binding data it sent, so other data makes it notice.
+      let forged: Vec<u8> = self.binding.iter().map(|byte| !byte).collect();
+      let rest = &without_proof["c=".len() + binding.len()..];
+      format!("{},{},c={}{}", self.client_first_bare, self.server_first, STANDARD.encode(forged), rest)
+    } else {
+      auth_message
+    };
+    let server_signature = hmac(&self.verifier.server_key, auth_message.as_bytes());
+    Ok(format!("v={}", STANDARD.encode(server_signature)))
+  }
+}
+
+/// Error for a SCRAM message that does not follow the specification.
+fn malformed(detail: &str) -> Errors {
+  Errors::ProtocolViolation {
+    message: format!("malformed SCRAM message: {}", detail),
+  }
+}
+
+/// Derives the salted password, `Hi()` in RFC 5802, out of the password normalized with SASLprep or, if it can't be normalized, as is.
+fn salted_password(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+  let password = stringprep::saslprep(password)
+    .map(|password| password.into_owned())
+    .unwrap_or_else(|_| password.to_owned());
+  let mut block = hmac(password.as_bytes(), &[salt, &1u32.to_be_bytes()].concat());
+  let mut result = block.clone();
+  for _ in 1..iterations {
+    block = hmac(password.as_bytes(), &block);
+    result.iter_mut().zip(&block).for_each(|(result, byte)| *result ^= byte);
+  }
+  result
+}
+
+/// HMAC-SHA-256 of a message.
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+  mac.update(message);
+  mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::{Binding, Exchange, Verifier};
+  use crate::errors::Errors;
+
+  #[test]
+  fn test_verifier() {
+    // Verifier of the password 'pencil' as PostgreSQL stores it in pg_authid.
+    let stored = "SCRAM-SHA-256$4096:QSXCR+Q6sek8bf92$FO+9jBb3MUukt6jJnzjPZOWc5ow/Pu6JtPyju0aqaE8=:qxJ1SbmSAi5EcS0J5Ck/cKAm/+Ixa+Kwp63f4OHDgzo=";
+    let verifier = Verifier::parse(stored).unwrap();
+    assert_eq!(verifier.to_string(), stored);
+    assert!(verifier.verify_password("pencil"));
+    assert!(!verifier.verify_password("pen"));
+    assert_eq!(Verifier::new("pencil", &verifier.salt, 4096), verifier);
+
+    assert_eq!(Verifier::parse("pencil"), None);
+    assert_eq!(Verifier::parse("SCRAM-SHA-256$0:QSXCR+Q6sek8bf92$6W3l:SYxe"), None);
+  }
+
+  #[test]
+  fn test_exchange() {
+    // Exchange from RFC 7677, where the password is "pencil".
+    let verifier = Verifier::new("pencil", &base64_decode("W22ZaJ0SNY7soEsUEjb6gQ=="), 4096);
+    let (mut exchange, server_first) = Exchange::start(verifier.clone(), "user", Binding::Unsupported, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+    assert!(server_first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+    assert!(server_first.ends_with(",s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"));
+    // The nonce of the server is random so the one of the RFC is put back to check the proof.
+    exchange.nonce = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned();
+    exchange.server_first = format!("r={},s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096", exchange.nonce);
+    let server_final = exchange
+      .finish(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=")
+      .unwrap();
+    assert_eq!(server_final, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+    // With a wrong binding the proof is still accepted, but the signature of the server no longer matches.
+    let (mut exchange, _) = Exchange::start(verifier.clone(), "user", Binding::Unsupported, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+    exchange.nonce = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned();
+    exchange.server_first = format!("r={},s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096", exchange.nonce);
+    let server_final = exchange
+      .with_wrong_binding()
+      .finish(b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=")
+      .unwrap();
+    assert_ne!(server_final, "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+
+    // A wrong proof fails authentication.
+    let (exchange, server_first) = Exchange::start(verifier.clone(), "user", Binding::Unsupported, b"n,,n=,r=abc").unwrap();
+    let nonce = server_first.split(',').next().unwrap();
+    let client_final = format!("c=biws,{},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", nonce);
+    assert!(matches!(exchange.finish(client_final.as_bytes()), Err(Errors::InvalidPassword { user }) if user == "user"));
+
+    // Malformed messages are protocol violations.
+    for client_first in [&b"x,,n=,r=abc"[..], b"n,,r=abc", b"n,,n=,r=", b"p=tls-server-end-point,,n=,r=abc"] {
+      assert!(matches!(
+        Exchange::start(verifier.clone(), "user", Binding::Unsupported, client_first),
+        Err(Errors::ProtocolViolation { .. })
+      ));
+    }
+    assert!(matches!(
+      Exchange::start(verifier, "user", Binding::Unsupported, b"n,a=admin,n=,r=abc"),
+      Err(Errors::FeatureNotSupported { .. })
+    ));
+  }
+
+  #[test]
+  fn test_channel_binding() {
+    let verifier = Verifier::new("pencil", b"salt", 1);
+    let selected = Binding::Selected(vec![1, 2, 3]);
+    let client_final = |server_first: &str, binding: &[u8]| {
+      let nonce = server_first.split(',').next().unwrap();
+      format!("c={},{},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", base64_encode(binding), nonce)
+    };
+
+    // The client repeats the header and the binding data, then fails only because the proof is wrong.
+    let (exchange, server_first) = Exchange::start(verifier.clone(), "user", selected.clone(), b"p=tls-server-end-point,,n=,r=abc").unwrap();
+    let result = exchange.finish(client_final(&server_first, b"p=tls-server-end-point,,\x01\x02\x03").as_bytes());
+    assert!(matches!(result, Err(Errors::InvalidPassword { .. })));
+
+    // Binding data from another certificate fails the check.
+    let (exchange, server_first) = Exchange::start(verifier.clone(), "user", selected.clone(), b"p=tls-server-end-point,,n=,r=abc").unwrap();
+    let result = exchange.finish(client_final(&server_first, b"p=tls-server-end-point,,\x01\x02\x04").as_bytes());
+    assert!(matches!(result, Err(Errors::ProtocolViolation { message }) if message == "SCRAM channel binding check failed"));
+
+    // A client that supports channel binding but did not see SCRAM-SHA-256-PLUS reveals a downgrade.
+    assert!(matches!(
+      Exchange::start(verifier.clone(), "user", Binding::Offered, b"y,,n=,r=abc"),
+      Err(Errors::ProtocolViolation { message }) if message == "SCRAM channel binding negotiation error"
+    ));
+    assert!(Exchange::start(verifier.clone(), "user", Binding::Unsupported, b"y,,n=,r=abc").is_ok());
+    assert!(Exchange::start(verifier.clone(), "user", Binding::Offered, b"n,,n=,r=abc").is_ok());
+
+    // The flag must match the mechanism.
+    assert!(matches!(
+      Exchange::start(verifier.clone(), "user", selected.clone(), b"n,,n=,r=abc"),
+      Err(Errors::ProtocolViolation { .. })
+    ));
+    assert!(matches!(
+      Exchange::start(verifier.clone(), "user", selected, b"p=tls-unique,,n=,r=abc"),
+      Err(Errors::FeatureNotSupported { .. })
+    ));
+  }
+
+  fn base64_encode(value: &[u8]) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(value)
+  }
+
+  fn base64_decode(value: &str) -> Vec<u8> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.decode(value).unwrap()
+  }
+}
@@ -2,30 +2,356 @@
 //!
 //! Receives the startup packet, handles protocol negotiation and authenticates the user.
 
+use super::authenticator::{AuthenticationClient, AuthenticationError, Authenticator};
+use super::backend::BackendFrames;
+use super::frontend::{Cursor, FramedStream, FrontEndFrames};
+use super::legacy;
+use crate::cancel::{BackendKey, LONG_SECRET_KEY_LENGTH};
 use crate::errors::Errors;
+use crate::hba::{authentication_method, Client};
 use crate::stream::{slice_to_array, Stream};
+use crate::tls::ALPN_PROTOCOL;
+use crate::{AuthenticationType, Configuration, GssEncryption, ProtocolVersion, Role};
 use std::collections::HashMap;
+use tokio::io::AsyncWriteExt;
 
 static NAME_DATA_LEN: i32 = 64;
 static MAXIMUM_STARTUP_PACKET_LENGTH: usize = 10000;
-static SUPPORTED_PROTOCOL_VERSION: i32 = 196608;
+/// Longest secret key a CancelRequest may carry.
+static MAXIMUM_SECRET_KEY_LENGTH: usize = 256;
+/// Sent instead of a protocol version to ask for an encrypted connection, 1234 in the most significant 16 bits and 5679 in the least.
+static SSL_REQUEST_CODE: i32 = 80877103;
+/// Sent instead of a protocol version to ask for GSSAPI encryption, 1234 in the most significant 16 bits and 5680 in the least.
+static GSS_ENCRYPTION_REQUEST_CODE: i32 = 80877104;
+/// Sent instead of a protocol version to cancel a query, 1234 in the most significant 16 bits and 5678 in the least.
+static CANCEL_REQUEST_CODE: i32 = 80877102;
+/// Content type of the TLS record a client starting the handshake sends first.
+static TLS_HANDSHAKE: u8 = 0x16;
+/// Prefix of the startup parameters that are protocol options.
+static PROTOCOL_OPTION_PREFIX: &str = "_pq_.";
+
+/// Run-time parameters PostgreSQL reports after authentication that do not depend on the startup packet.
+static PARAMETER_STATUS: [(&str, &str); 9] = [
+  ("DateStyle", "ISO, MDY"),
+  ("default_transaction_read_only", "off"),
+  ("in_hot_standby", "off"),
+  ("integer_datetimes", "on"),
+  ("IntervalStyle", "postgres"),
+  ("server_encoding", "UTF8"),
+  ("server_version", "14.0"),
+  ("standard_conforming_strings", "on"),
+  ("TimeZone", "UTC"),
+];
+
+/// Outcome of the startup of a connection.
+pub(super) enum Startup {
+  /// Authenticated client speaking protocol 3, with the parameters sent in the startup packet.
+  Session(FramedStream, HashMap<String, String>),
+  /// Client speaking protocol 2.0, yet to be authenticated, with the parameters sent in the startup packet.
+  Legacy(Stream, HashMap<String, String>),
+  /// Client that only asked to cancel the query of another backend.
+  Cancel,
+}
+
+/// Receives the startup packet and authenticates the user, leaving the connection ready to receive queries.
+///
+/// Errors are reported to the client with a FATAL ErrorResponse before being returned, after which the connection must be closed.
+/// Clients speaking protocol 2.0 are handed back as soon as their startup packet was read, since every message that follows differs.
+/// * `stream` - Connection just accepted by the Postmaster.
+/// * `configuration` - Configuration used to define the backend process.
+/// * `key` - Identity of the backend, reported to the client once authenticated.
+pub(super) async fn startup(stream: Stream, configuration: &Configuration, key: &mut BackendKey) -> Result<Startup, Errors> {
+  let mut stream = direct_tls(stream, configuration).await?;
+  let mut gss_done = false;
+  let (protocol_version, parameters, options) = loop {
+    let frame = match stream.read_first_packet().await {
+      Ok(frame) => frame,
+      Err(error) => return Err(fatal(&mut stream, error).await),
+    };
+    match frame {
+      FrontEndFrames::StartupMessage {
+        protocol_version,
+        parameters,
+        options,
+      } => break (protocol_version, parameters, options),
+      // The client gets no answer whether the request matched a backend or not.
+      FrontEndFrames::CancelRequest { process_id, secret_key } => {
+        let _ = key.registry().cancel(process_id, &secret_key);
+        return Ok(Startup::Cancel);
+      }
+      // Encryption is negotiated once, before the startup message, after which the client sends a new first packet.
+      FrontEndFrames::SSLRequest if !stream.is_tls() => match &configuration.tls {
+        Some(tls) => {
+          stream.write_all(b"S").await?;
+          stream = stream.upgrade(tls.server_config.clone()).await?;
+        }
+        None => stream.write_all(b"N").await?,
+      },
+      FrontEndFrames::SSLRequest => {
+        let error = unsupported_protocol(ProtocolVersion::from(SSL_REQUEST_CODE as u32), configuration);
+        return Err(fatal(&mut stream, error).await);
+      }
+      // Like SSLRequest, GSSENCRequest can't be repeated nor sent over a connection that is already encrypted.
+      FrontEndFrames::GSSENCRequest if !gss_done && !stream.is_tls() => {
+        gss_done = true;
+        match configuration.gss_encryption {
+          GssEncryption::Refuse => stream.write_all(b"N").await?,
+          GssEncryption::Drop => {
+            return Err(Errors::FeatureNotSupported {
+              message: "GSSAPI encryption not supported".to_owned(),
+            })
+          }
+          GssEncryption::Accept => {
+            // The client now starts exchanging GSSAPI tokens, which the mock can't take part in.
+            stream.write_all(b"G").await?;
+            stream.flush().await?;
+            return Err(Errors::FeatureNotSupported {
+              message: "GSSAPI encryption not supported".to_owned(),
+            });
+          }
+        }
+      }
+      FrontEndFrames::GSSENCRequest => {
+        let error = unsupported_protocol(ProtocolVersion::from(GSS_ENCRYPTION_REQUEST_CODE as u32), configuration);
+        return Err(fatal(&mut stream, error).await);
+      }
+      _ => {
+        let error = Errors::ProtocolViolation {
+          message: "invalid startup packet layout".to_owned(),
+        };
+        return Err(fatal(&mut stream, error).await);
+      }
+    }
+  };
+
+  if protocol_version == ProtocolVersion::V2_0 {
+    if configuration.legacy_protocol {
+      return Ok(Startup::Legacy(stream, parameters));
+    }
+    // The client could not parse an error of protocol 3.
+    return Err(legacy::fatal(&mut stream, unsupported_protocol(protocol_version, configuration)).await);
+  }
+
+  let mut stream = FramedStream::new(stream);
+  match negotiate_protocol_version(protocol_version, configuration) {
+    Ok(version) => {
+      // Options the server does not know about are ignored once reported, rather than failing the connection.
+      let unrecognized: Vec<String> = options
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| !configuration.protocol_options.contains(name))
+        .collect();
+      if version != protocol_version || !unrecognized.is_empty() {
+        stream
+          .send(&[BackendFrames::NegotiateProtocolVersion {
+            minor_version: version.minor() as i32,
+            options: unrecognized,
+          }])
+          .await?;
+      }
+      if version >= ProtocolVersion::V3_2 {
+        key.renew_secret_key(LONG_SECRET_KEY_LENGTH);
+      }
+    }
+    Err(error) => return Err(fatal(stream.get_mut(), error).await),
+  }
+  let user = parameters["user"].clone();
+  let method = match authentication_method(configuration, &client(stream.get_ref(), &parameters)) {
+    Ok(method) => method,
+    Err(error) => return Err(fatal(stream.get_mut(), error).await),
+  };
+  let result = authenticator(configuration, &method)
+    .authenticate(&mut AuthenticationClient::new(&mut stream, configuration, &parameters))
+    .await;
+  if let Err(AuthenticationError(error)) = result {
+    return Err(fatal(stream.get_mut(), error).await);
+  }
+  stream.send(&[BackendFrames::AuthenticationOk]).await?;
+  let superuser = match log_in(configuration, key, &user) {
+    Ok(role) => role.superuser,
+    Err(error) => return Err(fatal(stream.get_mut(), error).await),
+  };
+
+  let mut frames = vec![
+    BackendFrames::ParameterStatus {
+      name: "application_name".to_owned(),
+      value: parameters.get("application_name").cloned().unwrap_or_default(),
+    },
+    BackendFrames::ParameterStatus {
+      name: "client_encoding".to_owned(),
+      value: parameters.get("client_encoding").cloned().unwrap_or_else(|| "UTF8".to_owned()),
+    },
+    BackendFrames::ParameterStatus {
+      name: "is_superuser".to_owned(),
+      value: if superuser { "on" } else { "off" }.to_owned(),
+    },
+    BackendFrames::ParameterStatus {
+      name: "session_authorization".to_owned(),
+      value: user,
+    },
+  ];
+  frames.extend(PARAMETER_STATUS.iter().map(|(name, value)| BackendFrames::ParameterStatus {
+    name: (*name).to_owned(),
+    value: (*value).to_owned(),
+  }));
+  frames.push(BackendFrames::BackendKeyData {
+    process_id: key.process_id(),
+    secret_key: key.secret_key().to_vec(),
+  });
+  stream.send(&frames).await?;
+  Ok(Startup::Session(stream, parameters))
+}
+
+/// Describes a client to choose its authentication method.
+/// * `stream` - Connection to the client.
+/// * `parameters` - Parameters of the startup packet, holding the database and the user.
+pub(super) fn client<'a>(stream: &Stream, parameters: &'a HashMap<String, String>) -> Client<'a> {
+  Client {
+    address: stream.peer_address(),
+    tls: stream.is_tls(),
+    database: &parameters["database"],
+    user: &parameters["user"],
+  }
+}
+
+/// Chooses what authenticates a client: the custom authenticator unless `pg_hba.conf` rules chose the method, otherwise the method.
+/// * `configuration` - Configuration holding the custom authenticator.
+/// * `method` - Authentication method chosen for the client.
+fn authenticator<'a>(configuration: &'a Configuration, method: &'a AuthenticationType) -> &'a dyn Authenticator {
+  match (&configuration.hba, &configuration.authenticator) {
+    (None, Some(authenticator)) => authenticator.as_ref(),
+    _ => method,
+  }
+}
+
+/// Checks that an authenticated client may start a session as the role it named, counting it against the connection limit of the role.
+/// * `configuration` - Configuration holding the roles.
+/// * `key` - Identity of the backend, which holds the session until it stops.
+/// * `user` - Role named in the startup packet.
+pub(super) fn log_in<'a>(configuration: &'a Configuration, key: &BackendKey, user: &str) -> Result<&'a Role, Errors> {
+  let role = configuration.role(user).ok_or_else(|| Errors::InvalidAuthorizationSpecification {
+    message: format!("role \"{}\" does not exist", user),
+  })?;
+  if !role.login {
+    return Err(Errors::InvalidAuthorizationSpecification {
+      message: format!("role \"{}\" is not permitted to log in", user),
+    });
+  }
+  let limit = if role.superuser { None } else { role.connection_limit };
+  if !key.log_in(user, limit) {
+    return Err(Errors::TooManyConnections {
+      message: format!("too many connections for role \"{}\"", user),
+    });
+  }
+  Ok(role)
+}
+
+/// Chooses the protocol version of the session out of the one the client asked for.
+///
+/// A client asking for a newer minor version than the server supports gets the newest the server supports, which the caller must report
+/// with NegotiateProtocolVersion. Anything else outside of the configured range is refused.
+/// Reference: https://www.postgresql.org/docs/18/protocol-overview.html#PROTOCOL-VERSIONS
+fn negotiate_protocol_version(requested: ProtocolVersion, configuration: &Configuration) -> Result<ProtocolVersion, Errors> {
+  let (minimum, maximum) = (configuration.minimum_protocol_version, configuration.maximum_protocol_version);
+  if requested.major() == maximum.major() && requested > maximum {
+    Ok(maximum)
+  } else if requested < minimum || requested > maximum {
+    Err(unsupported_protocol(requested, configuration))
+  } else {
+    Ok(requested)
+  }
+}
+
+/// Error refusing a protocol version, or a request sent where a startup packet with a version was expected.
+fn unsupported_protocol(requested: ProtocolVersion, configuration: &Configuration) -> Errors {
+  Errors::ProtocolViolation {
+    message: format!(
+      "unsupported frontend protocol {}: server supports {} to {}",
+      requested, configuration.minimum_protocol_version, configuration.maximum_protocol_version
+    ),
+  }
+}
+
+/// Performs the TLS handshake if the client started it right away instead of sending SSLRequest, as PostgreSQL 17 clients do with
+/// `sslnegotiation=direct`.
+///
+/// A startup packet begins with its length, whose most significant byte is always 0, so it can't be mistaken for a TLS record. The
+/// connection is closed without a response, which the client could not read anyway, when direct TLS is not allowed or the client did not
+/// negotiate the `postgresql` protocol through ALPN.
+/// Reference: https://www.postgresql.org/docs/17/protocol-flow.html#PROTOCOL-FLOW-SSL
+async fn direct_tls(mut stream: Stream, configuration: &Configuration) -> Result<Stream, Errors> {
+  let mut first = [0u8; 1];
+  if stream.read_full(&mut first).await? == 0 {
+    return Ok(stream);
+  }
+  let stream = Stream::Rewound {
+    prefix: first.to_vec(),
+    stream: Box::new(stream),
+  };
+  if first[0] != TLS_HANDSHAKE {
+    return Ok(stream);
+  }
+  let tls = match &configuration.tls {
+    Some(tls) if configuration.direct_tls => tls,
+    _ => {
+      return Err(Errors::ProtocolViolation {
+        message: "direct SSL connection not accepted".to_owned(),
+      })
+    }
+  };
+  let stream = stream.upgrade(tls.server_config.clone()).await?;
+  if stream.alpn_protocol() != Some(ALPN_PROTOCOL) {
+    return Err(Errors::ProtocolViolation {
+      message: "received direct SSL connection request without ALPN protocol negotiation extension".to_owned(),
+    });
+  }
+  Ok(stream)
+}
+
+/// Reports an error to the client unless the connection itself failed, returning the error so the caller can end the session.
+pub(super) async fn fatal(stream: &mut Stream, error: Errors) -> Errors {
+  if !matches!(error, Errors::Io { .. }) {
+    let _ = stream.write_all(&Vec::from(&BackendFrames::error("FATAL", &error))).await;
+    let _ = stream.flush().await;
+  }
+  error
+}
 
 impl Stream {
   /// PostgreSQL packets follow the TLV format except for the first packet which does not have a type.
   ///
   /// Instead of implementing a single read_all method with conditions to cater to a single packet it's easier to implement a separate method to
   /// handle that exception.
-  pub(crate) async fn read_first_packet(self) -> Result<Vec<u8>, Errors> {
-    todo!()
-  }
-}
+  ///
+  /// The declared length is validated before the body is buffered so a client can't make the backend allocate an arbitrary amount of memory.
+  /// Reference: https://www.postgresql.org/docs/14/protocol-flow.html#id-1.10.5.7.3
+  pub(crate) async fn read_first_packet(&mut self) -> Result<FrontEndFrames, Errors> {
+    let mut len = [0u8; 4];
+    let read = self.read_full(&mut len).await?;
+    if read < len.len() {
+      return Err(Errors::ProtocolViolation {
+        message: "incomplete startup packet".to_owned(),
+      });
+    }
 
-/// Represents packets received from the client.
-#[derive(Debug, PartialEq)]
-pub(crate) enum FrontEndFrames {
-  /// First message sent by the client when connection is opened. The only mandatory parameter is 'user' and an unknown number of parameters may be 
-  /// sent so we store everything in a HashMap.
-  StartupMessage { parameters: HashMap<String, String> },
+    // The length includes itself and must at least hold the protocol version.
+    let length = i32::from_be_bytes(len);
+    if length < 8 || length as usize > MAXIMUM_STARTUP_PACKET_LENGTH {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid length of startup packet".to_owned(),
+      });
+    }
+
+    let mut packet = vec![0u8; length as usize];
+    packet[..4].copy_from_slice(&len);
+    let read = self.read_full(&mut packet[4..]).await?;
+    if read < packet.len() - 4 {
+      return Err(Errors::ProtocolViolation {
+        message: "incomplete startup packet".to_owned(),
+      });
+    }
+    FrontEndFrames::try_from(&packet)
+  }
 }
 
 impl TryFrom<&Vec<u8>> for FrontEndFrames {
@@ -36,79 +362,133 @@ impl TryFrom<&Vec<u8>> for FrontEndFrames {
         message: "invalid length of startup packet".to_owned(),
       });
     }
-    
-    let len: Vec<u8> = packet.clone().into_iter().take(4).collect();
-    let length = i32::from_be_bytes(slice_to_array(&len[..4])) as usize;
+
+    if packet.len() < 8 {
+      return Err(Errors::ProtocolViolation {
+        message: "invalid length of startup packet".to_owned(),
+      });
+    }
+    let length = i32::from_be_bytes(slice_to_array(&packet[..4])) as usize;
     if length != packet.len() {
       return Err(Errors::ProtocolViolation {
         message: "incomplete startup packet".to_owned(),
       });
     }
 
-    if packet.last().unwrap() != &0 {
+    // Requests sent in place of a startup message carry only a code where the protocol version would be.
+    let code = i32::from_be_bytes(slice_to_array(&packet[4..8]));
+    if code == SSL_REQUEST_CODE || code == GSS_ENCRYPTION_REQUEST_CODE {
+      if length != 8 {
+        return Err(Errors::ProtocolViolation {
+          message: "invalid length of startup packet".to_owned(),
+        });
+      }
+      return Ok(if code == SSL_REQUEST_CODE {
+        FrontEndFrames::SSLRequest
+      } else {
+        FrontEndFrames::GSSENCRequest
+      });
+    }
+    // The secret key is 4 bytes long with protocol 3.0 and up to 256 bytes long from 3.2 on.
+    if code == CANCEL_REQUEST_CODE {
+      if !(16..=12 + MAXIMUM_SECRET_KEY_LENGTH).contains(&length) {
+        return Err(Errors::ProtocolViolation {
+          message: "invalid length of cancel request packet".to_owned(),
+        });
+      }
+      return Ok(FrontEndFrames::CancelRequest {
+        process_id: u32::from_be_bytes(slice_to_array(&packet[8..12])),
+        secret_key: packet[12..].to_vec(),
+      });
+    }
+
+    if packet.last() != Some(&0) {
       return Err(Errors::ProtocolViolation {
         message: "invalid startup packet layout: expected terminator as last byte".to_owned(),
       });
     }
     let mut parameters = HashMap::new();
-    let protocol_version_barray: Vec<u8> = packet.clone().into_iter().skip(4).take(4).collect();
-    let protocol_version = i32::from_be_bytes(slice_to_array(&protocol_version_barray[..4]));
-    if protocol_version != SUPPORTED_PROTOCOL_VERSION {
-      return Err(Errors::ProtocolViolation {
-        message: "unsupported frontend protocol".to_owned(),
-      });
+    let mut options = Vec::new();
+    // Whether the version is supported depends on the configuration, so it's checked once the packet was read.
+    let protocol_version = ProtocolVersion::from(code as u32);
+    if protocol_version == ProtocolVersion::V2_0 {
+      return legacy::startup_message(packet);
     }
-    let inserted = parameters.insert("protocol_version".to_owned(), protocol_version.to_string());
 
-    // Vector index.
-    let mut number_processed_bytes: usize = 8;
-    loop {
-      // Message ends with a null character so we know we're done processing by checking the length minus the last byte.
-      if number_processed_bytes == length - 1 {
-        break;
+    // Parameters are pairs of null terminated names and values, followed by the terminator of the packet.
+    let mut cursor = Cursor::new(&packet[8..length - 1]);
+    while !cursor.is_empty() {
+      let name = cursor.string()?;
+      if cursor.is_empty() {
+        return Err(Errors::ProtocolViolation {
+          message: format!("invalid startup packet layout: missing value for parameter \"{}\"", name),
+        });
+      }
+      let value = cursor.string()?;
+      if name.starts_with(PROTOCOL_OPTION_PREFIX) {
+        options.push((name, value));
+      } else {
+        let _ = parameters.insert(name, value);
       }
-      let mut name: Option<String> = None;
-      let mut value: Option<String> = None;
-      if let Some(name_terminating_byte) = packet.clone().into_iter().skip(number_processed_bytes).position(|x| x == 0) {
-        let tmp: Vec<u8> = packet
-          .clone()
-          .into_iter()
-          .skip(number_processed_bytes)
-          .take(name_terminating_byte)
-          .collect();
-        number_processed_bytes = number_processed_bytes + tmp.len() + 1;
-        name = Some(String::from_utf8(tmp).unwrap());
-      }
-      if let Some(value_terminating_byte) = packet.clone().into_iter().skip(number_processed_bytes).position(|x| x == 0) {
-        let tmp: Vec<u8> = packet
-          .clone()
-          .into_iter()
-          .skip(number_processed_bytes)
-          .take(value_terminating_byte)
-          .collect();
-        number_processed_bytes = number_processed_bytes + tmp.len() + 1;
-        value = Some(String::from_utf8(tmp).unwrap());
-      }
-      let inserted = parameters.insert(name.unwrap(), value.unwrap());
-    }
-    if let None = parameters.keys().into_iter().find(|&x| x == &"user".to_owned()) {
+    }
+    if !parameters.contains_key("user") {
       return Err(Errors::InvalidAuthorizationSpecification {
         message: "no PostgreSQL user name specified in startup packet".to_owned(),
       });
     }
-    if let None = parameters.keys().into_iter().find(|&x| x == &"database".to_owned()) {
-      let user = parameters[&"user".to_owned()].clone();
-      let inserted = parameters.insert("database".to_owned(), user.clone());
+    if !parameters.contains_key("database") {
+      let user = parameters["user"].clone();
+      let _ = parameters.insert("database".to_owned(), user);
     }
-    Ok(FrontEndFrames::StartupMessage { parameters })
+    Ok(FrontEndFrames::StartupMessage {
+      protocol_version,
+      parameters,
+      options,
+    })
   }
 }
 
 #[cfg(test)]
 mod tests {
 
-  use super::FrontEndFrames;
+  use super::{negotiate_protocol_version, FrontEndFrames};
   use crate::errors::Errors;
+  use crate::stream::tests::connected_pair;
+  use crate::{Configuration, ProtocolVersion};
+  use std::time::Duration;
+  use tokio::io::AsyncWriteExt;
+
+  #[test]
+  fn test_startup_message_try_from_malformed() {
+    // Startup packet of protocol 3.0 holding the parameters supplied.
+    let packet = |parameters: &[u8]| {
+      let mut packet = ((parameters.len() + 8) as i32).to_be_bytes().to_vec();
+      packet.extend_from_slice(&[0x00, 0x03, 0x00, 0x00]);
+      packet.extend_from_slice(parameters);
+      packet
+    };
+    assert!(FrontEndFrames::try_from(&packet(b"user\0bob\0\0")).is_ok());
+
+    // Names and values must be valid UTF-8.
+    for parameters in [&b"us\xffer\0bob\0\0"[..], &b"user\0b\xc3\0\0"[..]] {
+      match FrontEndFrames::try_from(&packet(parameters)) {
+        Err(Errors::ProtocolViolation { message }) => assert_eq!(message, "invalid byte sequence for encoding \"UTF8\""),
+        result => panic!("Unexpected result {:?}", result),
+      }
+    }
+
+    // Every name must be followed by a value.
+    match FrontEndFrames::try_from(&packet(b"user\0bob\0extra\0\0")) {
+      Err(Errors::ProtocolViolation { message }) => {
+        assert_eq!(message, "invalid startup packet layout: missing value for parameter \"extra\"")
+      }
+      result => panic!("Unexpected result {:?}", result),
+    }
+
+    // Packets too short to hold a protocol version.
+    let result = FrontEndFrames::try_from(&vec![0x00, 0x00, 0x00, 0x04]);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+  }
 
   #[test]
   fn test_startup_message_try_from() {
@@ -125,7 +505,13 @@ mod tests {
       0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00,
     ];
     match FrontEndFrames::try_from(&packet) {
-      Ok(FrontEndFrames::StartupMessage { parameters }) => {
+      Ok(FrontEndFrames::StartupMessage {
+        protocol_version,
+        parameters,
+        options,
+      }) => {
+        assert_eq!(protocol_version, ProtocolVersion::V3_0);
+        assert!(options.is_empty());
         assert_eq!(parameters["user"], "ruipacheco".to_owned());
         assert_eq!(parameters["database"], "postgres".to_owned());
         assert_eq!(parameters["application_name"], "psql".to_owned());
@@ -143,13 +529,13 @@ mod tests {
     /*
     // TODO Missing 'user' data - incomplete, define length as 70 bytes
     let packet = vec![
-      0x00, 0x00, 0x00, 0x56, 0x00, 0x03, 0x00, 0x00, 
+      0x00, 0x00, 0x00, 0x56, 0x00, 0x03, 0x00, 0x00,
       0x64, 0x61, 0x74, 0x61, 0x62, 0x61, 0x73, 0x65, 0x00, 0x70, 0x6f, 0x73, 0x74, 0x67, 0x72, 0x65, 0x73, 0x00, 0x61, 0x70, 0x70, 0x6c, 0x69, 0x63,
       0x61, 0x74, 0x69, 0x6f, 0x6e, 0x5f, 0x6e, 0x61, 0x6d, 0x65, 0x00, 0x70, 0x73, 0x71, 0x6c, 0x00, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x5f, 0x65,
       0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00,
     ];
     let result = FrontEndFrames::try_from(&packet);
-    assert!(matches!(result, Err(Errors::InvalidAuthorizationSpecification { message })));
+    assert!(matches!(result, Err(Errors::InvalidAuthorizationSpecification { .. })));
     */
 
     // Packet without null terminator as last byte.
@@ -160,7 +546,7 @@ mod tests {
       0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00, 0x71,
     ];
     let result = FrontEndFrames::try_from(&packet);
-    assert!(matches!(result, Err(Errors::ProtocolViolation { message })));
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
 
     // Packet with declared length different from real length.
     let packet = vec![
@@ -170,7 +556,7 @@ mod tests {
       0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00,
     ];
     let result = FrontEndFrames::try_from(&packet);
-    assert!(matches!(result, Err(Errors::ProtocolViolation { message })));
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
 
     // Packet with more than 10000 bytes.
     let mut packet: Vec<u8> = Vec::new();
@@ -181,9 +567,34 @@ mod tests {
       packet.push(0x00);
     }
     let result = FrontEndFrames::try_from(&packet);
-    assert!(matches!(result, Err(Errors::ProtocolViolation { message })));
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
 
-    // Unsupported protocol.
+    // SSLRequest.
+    let packet = vec![0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x2f];
+    assert_eq!(FrontEndFrames::try_from(&packet).unwrap(), FrontEndFrames::SSLRequest);
+
+    // SSLRequest with trailing data.
+    let packet = vec![0x00, 0x00, 0x00, 0x09, 0x04, 0xd2, 0x16, 0x2f, 0x00];
+    let result = FrontEndFrames::try_from(&packet);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
+
+    // GSSENCRequest.
+    let packet = vec![0x00, 0x00, 0x00, 0x08, 0x04, 0xd2, 0x16, 0x30];
+    assert_eq!(FrontEndFrames::try_from(&packet).unwrap(), FrontEndFrames::GSSENCRequest);
+
+    // CancelRequest.
+    let packet = vec![
+      0x00, 0x00, 0x00, 0x10, 0x04, 0xd2, 0x16, 0x2e, 0x00, 0x00, 0x30, 0x39, 0x01, 0x02, 0x03, 0x04,
+    ];
+    assert_eq!(
+      FrontEndFrames::try_from(&packet).unwrap(),
+      FrontEndFrames::CancelRequest {
+        process_id: 12345,
+        secret_key: vec![0x01, 0x02, 0x03, 0x04]
+      }
+    );
+
+    // Versions are only checked against the configuration once the packet was read.
     let packet = vec![
       0x00, 0x00, 0x00, 0x56, 0x00, 0x04, 0x00, 0x00, 0x75, 0x73, 0x65, 0x72, 0x00, 0x72, 0x75, 0x69, 0x70, 0x61, 0x63, 0x68, 0x65, 0x63, 0x6f, 0x00,
       0x64, 0x61, 0x74, 0x61, 0x62, 0x61, 0x73, 0x65, 0x00, 0x70, 0x6f, 0x73, 0x74, 0x67, 0x72, 0x65, 0x73, 0x00, 0x61, 0x70, 0x70, 0x6c, 0x69, 0x63,
@@ -191,11 +602,105 @@ mod tests {
       0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00,
     ];
     let result = FrontEndFrames::try_from(&packet);
-    assert!(matches!(result, Err(Errors::ProtocolViolation { message })));
+    assert!(matches!(result, Ok(FrontEndFrames::StartupMessage { protocol_version, .. }) if protocol_version == ProtocolVersion::new(4, 0)));
+
+    // Protocol options are kept apart from run-time parameters.
+    let packet = crate::v3::tests::startup_packet(&[("user", "postgres"), ("_pq_.b", "1"), ("_pq_.a", "2")]);
+    match FrontEndFrames::try_from(&packet) {
+      Ok(FrontEndFrames::StartupMessage { parameters, options, .. }) => {
+        assert_eq!(parameters.len(), 2);
+        assert_eq!(
+          options,
+          vec![("_pq_.b".to_owned(), "1".to_owned()), ("_pq_.a".to_owned(), "2".to_owned())]
+        );
+      }
+      _ => panic!("Wrong enum!"),
+    }
+
+    // CancelRequest with a secret key longer than 256 bytes.
+    let mut packet = vec![0x00, 0x00, 0x01, 0x0d, 0x04, 0xd2, 0x16, 0x2e, 0x00, 0x00, 0x30, 0x39];
+    packet.resize(269, 0x01);
+    let result = FrontEndFrames::try_from(&packet);
+    assert!(matches!(result, Err(Errors::ProtocolViolation { .. })));
   }
 
   #[test]
-  fn test_reading_startup_packet() {
-    todo!()
+  fn test_negotiate_protocol_version() {
+    let configuration = Configuration::default();
+    assert_eq!(
+      negotiate_protocol_version(ProtocolVersion::V3_0, &configuration).unwrap(),
+      ProtocolVersion::V3_0
+    );
+    // Newer minor versions are downgraded.
+    assert_eq!(
+      negotiate_protocol_version(ProtocolVersion::V3_2, &configuration).unwrap(),
+      ProtocolVersion::V3_0
+    );
+    let result = negotiate_protocol_version(ProtocolVersion::new(4, 0), &configuration);
+    assert!(
+      matches!(result, Err(Errors::ProtocolViolation { message }) if message == "unsupported frontend protocol 4.0: server supports 3.0 to 3.0")
+    );
+
+    let configuration = Configuration::default().with_protocol_versions(ProtocolVersion::V3_2, ProtocolVersion::V3_2);
+    assert_eq!(
+      negotiate_protocol_version(ProtocolVersion::V3_2, &configuration).unwrap(),
+      ProtocolVersion::V3_2
+    );
+    let result = negotiate_protocol_version(ProtocolVersion::V3_0, &configuration);
+    assert!(
+      matches!(result, Err(Errors::ProtocolViolation { message }) if message == "unsupported frontend protocol 3.0: server supports 3.2 to 3.2")
+    );
+  }
+
+  /// Startup packet sent by psql, see `test_startup_message_try_from`.
+  fn startup_packet() -> Vec<u8> {
+    vec![
+      0x00, 0x00, 0x00, 0x56, 0x00, 0x03, 0x00, 0x00, 0x75, 0x73, 0x65, 0x72, 0x00, 0x72, 0x75, 0x69, 0x70, 0x61, 0x63, 0x68, 0x65, 0x63, 0x6f, 0x00,
+      0x64, 0x61, 0x74, 0x61, 0x62, 0x61, 0x73, 0x65, 0x00, 0x70, 0x6f, 0x73, 0x74, 0x67, 0x72, 0x65, 0x73, 0x00, 0x61, 0x70, 0x70, 0x6c, 0x69, 0x63,
+      0x61, 0x74, 0x69, 0x6f, 0x6e, 0x5f, 0x6e, 0x61, 0x6d, 0x65, 0x00, 0x70, 0x73, 0x71, 0x6c, 0x00, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x5f, 0x65,
+      0x6e, 0x63, 0x6f, 0x64, 0x69, 0x6e, 0x67, 0x00, 0x55, 0x54, 0x46, 0x38, 0x00, 0x00,
+    ]
+  }
+
+  #[tokio::test]
+  async fn test_reading_startup_packet() {
+    // Packet split over several segments, including one in the middle of the length.
+    let (mut stream, mut client) = connected_pair().await;
+    let packet = startup_packet();
+    let writer = tokio::spawn(async move {
+      for chunk in [&packet[..2], &packet[2..9], &packet[9..40], &packet[40..]] {
+        client.write_all(chunk).await.unwrap();
+        client.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+      client
+    });
+    match stream.read_first_packet().await {
+      Ok(FrontEndFrames::StartupMessage { parameters, .. }) => {
+        assert_eq!(parameters["user"], "ruipacheco".to_owned());
+        assert_eq!(parameters["client_encoding"], "UTF8".to_owned());
+      }
+      _ => panic!("Wrong enum!"),
+    }
+    let _client = writer.await.unwrap();
+
+    // Declared length above the maximum is rejected before the body is sent.
+    let (mut stream, mut client) = connected_pair().await;
+    client.write_all(&10001i32.to_be_bytes()).await.unwrap();
+    let result = stream.read_first_packet().await;
+    assert!(matches!(result, Err(Errors::ProtocolViolation { message }) if message == "invalid length of startup packet"));
+
+    // Declared length too short to hold the protocol version.
+    let (mut stream, mut client) = connected_pair().await;
+    client.write_all(&4i32.to_be_bytes()).await.unwrap();
+    let result = stream.read_first_packet().await;
+    assert!(matches!(result, Err(Errors::ProtocolViolation { message }) if message == "invalid length of startup packet"));
+
+    // Client disconnects before sending the whole packet.
+    let (mut stream, mut client) = connected_pair().await;
+    client.write_all(&startup_packet()[..20]).await.unwrap();
+    drop(client);
+    let result = stream.read_first_packet().await;
+    assert!(matches!(result, Err(Errors::ProtocolViolation { message }) if message == "incomplete startup packet"));
   }
 }
@@ -1,26 +1,500 @@
 //! Integration tests for the library.
 
-use rustgres::{AuthenticationType, Configuration, Postmaster};
+use rustgres::{
+  AuthenticationClient, AuthenticationError, AuthenticationFuture, AuthenticationRequest, AuthenticationType, Authenticator, Configuration,
+  Postmaster, Role,
+};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpStream;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{NoTls, SimpleQueryMessage};
+
+/// Configuration listening on a port picked by the operating system.
+fn ephemeral_configuration() -> Configuration {
+  Configuration::new(
+    "postgres".to_owned(),
+    None,
+    None,
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+    AuthenticationType::Trust,
+  )
+}
+
+/// Creates an empty directory to hold Unix domain sockets, unique to the test.
+fn socket_directory(name: &str) -> PathBuf {
+  let directory = std::env::temp_dir().join(format!("rustgres-{}-{}", std::process::id(), name));
+  let _ = std::fs::remove_dir_all(&directory);
+  std::fs::create_dir_all(&directory).unwrap();
+  directory
+}
+
+#[tokio::test]
+async fn test_server_default_configuration() {
+  // The default configuration listens on the default PostgreSQL port, which is not bound so the test can't collide with a server.
+  assert_eq!(Configuration::default().hostaddr(), "127.0.0.1:5432".parse().unwrap());
+
+  // With port 0 the handle reports the port the operating system picked, which clients reach the server on.
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+  let address = server.address();
+  assert_ne!(address.port(), 0);
+  let stream = TcpStream::connect(address).await.unwrap();
+  assert_eq!(stream.peer_addr().unwrap(), address);
+}
+
+#[tokio::test]
+async fn test_server_custom_configuration() {
+  let postmaster = Postmaster::new(ephemeral_configuration());
+  let server = postmaster.spawn().await.unwrap();
+  assert_ne!(server.address().port(), 0);
+
+  let (client, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+    .await
+    .unwrap();
+  let connection = tokio::spawn(connection);
+  let messages = client.simple_query("SELECT 1").await.unwrap();
+  assert!(matches!(messages.last(), Some(SimpleQueryMessage::CommandComplete(0))));
+  drop(client);
+  assert!(connection.await.unwrap().is_ok());
+  assert!(server.shutdown().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_server_concurrent_clients() {
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+  let mut clients = Vec::new();
+  for _ in 0..4 {
+    let (client, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+      .await
+      .unwrap();
+    tokio::spawn(connection);
+    clients.push(client);
+  }
+  for client in &clients {
+    assert!(client.batch_execute("BEGIN; COMMIT").await.is_ok());
+  }
+}
+
+#[tokio::test]
+async fn test_server_shutdown() {
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+  let address = server.address();
+  assert!(TcpStream::connect(address).await.is_ok());
+  assert!(server.shutdown().await.is_ok());
+  assert!(TcpStream::connect(address).await.is_err());
+
+  // Dropping the handle also stops the server.
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+  let address = server.address();
+  drop(server);
+  tokio::task::yield_now().await;
+  let mut refused = false;
+  for _ in 0..100 {
+    if TcpStream::connect(address).await.is_err() {
+      refused = true;
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  assert!(refused);
+}
 
-// The default configuration uses the default PostgreSQL port so if this test fails check if the server is not running in the background.
 #[test]
-fn test_server_default_configuration() {
-  let postmaster = Postmaster::default();
-  let started = postmaster.start();
-  assert!(started.is_ok());
+fn test_background_server() {
+  let server = Postmaster::new(ephemeral_configuration()).start_in_background().unwrap();
+  assert_ne!(server.address().port(), 0);
+
+  let mut client = postgres::Client::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), postgres::NoTls).unwrap();
+  assert!(client.batch_execute("BEGIN; COMMIT").is_ok());
+  assert_eq!(client.execute("UPDATE t SET a = 1", &[]).unwrap(), 0);
+  client.close().unwrap();
+
+  let address = server.address();
+  assert!(server.stop().is_ok());
+  assert!(std::net::TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn test_background_server_stops_on_drop() {
+  let server = Postmaster::new(ephemeral_configuration()).start_in_background().unwrap();
+  let address = server.address();
+  let _client = postgres::Client::connect(&format!("host=127.0.0.1 port={} user=postgres", address.port()), postgres::NoTls).unwrap();
+  drop(server);
+  assert!(std::net::TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_err());
 }
 
 #[test]
-fn test_server_custom_configuration() {
+fn test_background_server_address_in_use() {
+  let server = Postmaster::new(ephemeral_configuration()).start_in_background().unwrap();
+  let configuration = Configuration::new("postgres".to_owned(), None, None, server.address(), AuthenticationType::Trust);
+  assert!(Postmaster::new(configuration).start_in_background().is_err());
+}
+
+#[tokio::test]
+async fn test_server_unix_socket() {
+  let directory = socket_directory("unix");
+  let configuration = ephemeral_configuration().with_unix_socket(directory.clone(), 0);
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let socket_path = directory.join(format!(".s.PGSQL.{}", server.address().port()));
+  assert_eq!(server.socket_path(), Some(socket_path.as_path()));
+  assert!(socket_path.exists());
+
+  // Clients can use both sockets at the same time.
+  let (unix_client, connection) = tokio_postgres::connect(
+    &format!("host={} port={} user=postgres", directory.display(), server.address().port()),
+    NoTls,
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  let (tcp_client, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  assert!(unix_client.batch_execute("SELECT 1").await.is_ok());
+  assert!(tcp_client.batch_execute("SELECT 1").await.is_ok());
+
+  assert!(server.shutdown().await.is_ok());
+  assert!(!socket_path.exists());
+  std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_background_server_unix_socket() {
+  let directory = socket_directory("background-unix");
+  let configuration = ephemeral_configuration().with_unix_socket(directory.clone(), 6543);
+  let server = Postmaster::new(configuration).start_in_background().unwrap();
+  let socket_path = directory.join(".s.PGSQL.6543");
+  assert!(socket_path.exists());
+
+  let mut client = postgres::Client::connect(&format!("host={} port=6543 user=postgres", directory.display()), postgres::NoTls).unwrap();
+  assert!(client.batch_execute("SELECT 1").is_ok());
+
+  // A second server can't take over a socket in use.
+  let configuration = ephemeral_configuration().with_unix_socket(directory.clone(), 6543);
+  assert!(Postmaster::new(configuration).start_in_background().is_err());
+  assert!(socket_path.exists());
+
+  drop(server);
+  assert!(!socket_path.exists());
+  std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_in_memory() {
+  let mut postmaster = Postmaster::new(ephemeral_configuration());
+  let stream = postmaster.connect_in_memory();
+  let (client, connection) = "user=postgres"
+    .parse::<tokio_postgres::Config>()
+    .unwrap()
+    .connect_raw(stream, NoTls)
+    .await
+    .unwrap();
+  let connection = tokio::spawn(connection);
+  assert!(client.batch_execute("BEGIN; COMMIT").await.is_ok());
+  let statement = client.prepare("DELETE FROM t").await.unwrap();
+  assert_eq!(client.execute(&statement, &[]).await.unwrap(), 0);
+  drop(client);
+  assert!(connection.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_cancel_query() {
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+  let (client, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  let (other, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+
+  let cancel_token = client.cancel_token();
+  let query = tokio::spawn(async move {
+    let result = client.simple_query("SELECT pg_sleep(60)").await;
+    (client, result)
+  });
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  cancel_token.cancel_query(NoTls).await.unwrap();
+  let (client, result) = query.await.unwrap();
+  assert_eq!(result.unwrap_err().code(), Some(&SqlState::QUERY_CANCELED));
+
+  // The session carries on and other sessions are unaffected.
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+  assert!(other.batch_execute("SELECT pg_sleep(0.1)").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_cleartext_password() {
   let configuration = Configuration::new(
     "postgres".to_owned(),
+    Some("secret".to_owned()),
     None,
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+    AuthenticationType::AuthenticationCleartextPassword,
+  );
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=127.0.0.1 port={} user=postgres password=secret", server.address().port()),
+    NoTls,
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  let result = tokio_postgres::connect(
+    &format!("host=127.0.0.1 port={} user=postgres password=wrong", server.address().port()),
+    NoTls,
+  )
+  .await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+}
+
+#[tokio::test]
+async fn test_md5_password() {
+  let configuration = Configuration::new(
+    "postgres".to_owned(),
+    Some("md553f48b7c4b76a86ce72276c5755f217d".to_owned()),
     None,
-    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3000),
-    AuthenticationType::Trust,
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+    AuthenticationType::AuthenticationMD5Password,
   );
-  let postmaster = Postmaster::new(configuration);
-  let started = postmaster.start();
-  assert!(started.is_ok());
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=127.0.0.1 port={} user=postgres password=secret", server.address().port()),
+    NoTls,
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  let result = tokio_postgres::connect(
+    &format!("host=127.0.0.1 port={} user=postgres password=wrong", server.address().port()),
+    NoTls,
+  )
+  .await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+}
+
+#[tokio::test]
+async fn test_scram_password() {
+  // The verifier PostgreSQL stores for the password "pencil".
+  let verifier = "SCRAM-SHA-256$4096:QSXCR+Q6sek8bf92$FO+9jBb3MUukt6jJnzjPZOWc5ow/Pu6JtPyju0aqaE8=:qxJ1SbmSAi5EcS0J5Ck/cKAm/+Ixa+Kwp63f4OHDgzo=";
+  for (password, iterations) in [("pencil", 4096), (verifier, 4096), ("pencil", 1)] {
+    let configuration = Configuration::new(
+      "postgres".to_owned(),
+      Some(password.to_owned()),
+      None,
+      SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+      AuthenticationType::AuthenticationSASL,
+    )
+    .with_scram_iterations(iterations)
+    .unwrap();
+    let server = Postmaster::new(configuration).spawn().await.unwrap();
+    let (client, connection) = tokio_postgres::connect(
+      &format!("host=127.0.0.1 port={} user=postgres password=pencil", server.address().port()),
+      NoTls,
+    )
+    .await
+    .unwrap();
+    tokio::spawn(connection);
+    assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+    let result = tokio_postgres::connect(
+      &format!("host=127.0.0.1 port={} user=postgres password=wrong", server.address().port()),
+      NoTls,
+    )
+    .await;
+    assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+  }
+}
+
+#[tokio::test]
+async fn test_roles() {
+  let configuration = |authentication_type: AuthenticationType| {
+    Configuration::new(
+      "postgres".to_owned(),
+      None,
+      None,
+      SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+      authentication_type,
+    )
+    .with_role(Role::new("alice".to_owned()).with_password("secret".to_owned()))
+    .with_role(
+      Role::new("bob".to_owned())
+        .with_password("secret".to_owned())
+        .with_valid_until(SystemTime::now() - Duration::from_secs(60)),
+    )
+  };
+  let server = Postmaster::new(configuration(AuthenticationType::Trust)).spawn().await.unwrap();
+  let connect = |user: &str| format!("host=127.0.0.1 port={} user={} password=secret", server.address().port(), user);
+
+  // Roles that do not exist can't log in.
+  let result = tokio_postgres::connect(&connect("carol"), NoTls).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)));
+
+  // Passwords are only checked by password authentication.
+  let (client, connection) = tokio_postgres::connect(&connect("bob"), NoTls).await.unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  let server = Postmaster::new(configuration(AuthenticationType::AuthenticationMD5Password))
+    .spawn()
+    .await
+    .unwrap();
+  let connect = |user: &str| format!("host=127.0.0.1 port={} user={} password=secret", server.address().port(), user);
+  let (client, connection) = tokio_postgres::connect(&connect("alice"), NoTls).await.unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  // An expired password fails like a wrong one, and so does a role that does not exist, which is not revealed.
+  for user in ["bob", "carol"] {
+    let result = tokio_postgres::connect(&connect(user), NoTls).await;
+    assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+  }
+}
+
+#[tokio::test]
+async fn test_hba() {
+  let directory = socket_directory("hba");
+  let configuration = ephemeral_configuration()
+    .with_unix_socket(directory.clone(), 0)
+    .with_role(Role::new("alice".to_owned()).with_password("secret".to_owned()))
+    .with_hba(
+      r#"
+# TYPE  DATABASE  USER      ADDRESS       METHOD
+local   all       all                     trust
+host    all       alice     127.0.0.1/32  scram-sha-256
+host    all       postgres  127.0.0.0/8   trust
+"#,
+    )
+    .unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let port = server.address().port();
+
+  // alice needs a password over TCP but not over the Unix domain socket.
+  let result = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=alice password=wrong", port), NoTls).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+  for host in ["127.0.0.1".to_owned(), directory.display().to_string()] {
+    let (client, connection) = tokio_postgres::connect(&format!("host={} port={} user=alice password=secret", host, port), NoTls)
+      .await
+      .unwrap();
+    tokio::spawn(connection);
+    assert!(client.batch_execute("SELECT 1").await.is_ok());
+  }
+
+  // No rule lets bob in over TCP.
+  let result = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=bob dbname=db", port), NoTls).await;
+  match result {
+    Err(error) => {
+      let error = error.as_db_error().unwrap();
+      assert_eq!(error.code(), &SqlState::INVALID_AUTHORIZATION_SPECIFICATION);
+      assert_eq!(error.severity(), "FATAL");
+      assert_eq!(
+        error.message(),
+        "no pg_hba.conf entry for host \"127.0.0.1\", user \"bob\", database \"db\", no encryption"
+      );
+    }
+    Ok(_) => panic!("bob connected"),
+  }
+  drop(server);
+  std::fs::remove_dir_all(directory).unwrap();
+}
+
+/// Asks for a password and accepts any, but only on the third attempt.
+#[derive(Debug, Default)]
+struct ThirdAttempt {
+  attempts: AtomicU32,
+}
+
+impl Authenticator for ThirdAttempt {
+  fn authenticate<'a>(&'a self, client: &'a mut AuthenticationClient<'_>) -> AuthenticationFuture<'a> {
+    Box::pin(async move {
+      client.send(AuthenticationRequest::CleartextPassword).await?;
+      assert_eq!(client.read_response().await?, b"secret\0");
+      if self.attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+        return Err(AuthenticationError::invalid_password(client.user()));
+      }
+      Ok(())
+    })
+  }
+}
+
+/// Refuses every client after a delay, with its own SQLSTATE and message.
+#[derive(Debug)]
+struct Refuse {
+  delay: Duration,
+  code: &'static str,
+  message: &'static str,
+}
+
+impl Authenticator for Refuse {
+  fn authenticate<'a>(&'a self, client: &'a mut AuthenticationClient<'_>) -> AuthenticationFuture<'a> {
+    Box::pin(async move {
+      assert_eq!(client.parameters()["database"], "postgres");
+      tokio::time::sleep(self.delay).await;
+      Err(AuthenticationError::new(self.code.to_owned(), self.message.to_owned()))
+    })
+  }
+}
+
+#[tokio::test]
+async fn test_authenticator() {
+  let server = Postmaster::new(ephemeral_configuration().with_authenticator(ThirdAttempt::default()))
+    .spawn()
+    .await
+    .unwrap();
+  let url = format!("host=127.0.0.1 port={} user=postgres password=secret", server.address().port());
+  for _ in 0..2 {
+    let result = tokio_postgres::connect(&url, NoTls).await;
+    assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_PASSWORD)));
+  }
+  let (client, connection) = tokio_postgres::connect(&url, NoTls).await.unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  // Refusals can be delayed and carry any SQLSTATE, such as one asking the user to change an expired password.
+  let authenticator = Refuse {
+    delay: Duration::from_millis(200),
+    code: "28P01",
+    message: "password expired, change it before connecting",
+  };
+  let server = Postmaster::new(ephemeral_configuration().with_authenticator(authenticator))
+    .spawn()
+    .await
+    .unwrap();
+  let start = Instant::now();
+  let result = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls).await;
+  assert!(start.elapsed() >= Duration::from_millis(200));
+  match result {
+    Err(error) => {
+      let error = error.as_db_error().unwrap();
+      assert_eq!(error.code(), &SqlState::INVALID_PASSWORD);
+      assert_eq!(error.message(), "password expired, change it before connecting");
+    }
+    Ok(_) => panic!("the authenticator refuses every client"),
+  }
+
+  // The built-in authentication types are authenticators too, and pg_hba.conf rules take precedence over the custom one.
+  let configuration = ephemeral_configuration()
+    .with_authenticator(AuthenticationType::Reject)
+    .with_hba("host all all 127.0.0.1/32 trust")
+    .unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let (client, connection) = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls)
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+  let server = Postmaster::new(ephemeral_configuration().with_authenticator(AuthenticationType::Reject))
+    .spawn()
+    .await
+    .unwrap();
+  let result = tokio_postgres::connect(&format!("host=127.0.0.1 port={} user=postgres", server.address().port()), NoTls).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)));
 }
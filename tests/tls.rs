@@ -0,0 +1,470 @@
+//! Integration tests for encrypted connections.
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+use rustgres::{AuthenticationType, ChannelBinding, Configuration, Postmaster, Role};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::config::SslNegotiation;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::tls::{ChannelBinding as ClientChannelBinding, MakeTlsConnect, TlsConnect, TlsStream};
+use tokio_postgres::{Config, NoTls};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::client;
+use tokio_rustls::rustls::client::WantsClientCert;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, ConfigBuilder, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Configuration listening on a port picked by the operating system.
+fn ephemeral_configuration() -> Configuration {
+  Configuration::new(
+    "postgres".to_owned(),
+    None,
+    None,
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+    AuthenticationType::Trust,
+  )
+}
+
+/// Creates a CA and a certificate it signed for `localhost`, returning the CA certificate, the server certificate and its key as PEM.
+fn certificates() -> (String, String, String) {
+  let ca_key = KeyPair::generate().unwrap();
+  let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+  ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+  let ca = ca_params.self_signed(&ca_key).unwrap();
+  let key = KeyPair::generate().unwrap();
+  let certificate = CertificateParams::new(vec!["localhost".to_owned()])
+    .unwrap()
+    .signed_by(&key, &ca, &ca_key)
+    .unwrap();
+  (ca.pem(), certificate.pem(), key.serialize_pem())
+}
+
+/// Client side TLS that trusts only the CA supplied.
+fn connector(ca: &str) -> MakeRustlsConnect {
+  MakeRustlsConnect::new(client_config(ca))
+}
+
+/// Client side TLS that trusts only the CA supplied and negotiates `postgresql` through ALPN, as direct TLS requires.
+fn direct_connector(ca: &str) -> MakeRustlsConnect {
+  let mut config = client_config(ca);
+  config.alpn_protocols = vec![b"postgresql".to_vec()];
+  MakeRustlsConnect::new(config)
+}
+
+/// Client configuration that trusts only the CA supplied.
+fn client_config(ca: &str) -> ClientConfig {
+  client_config_builder(ca).with_no_client_auth()
+}
+
+/// Client side TLS that trusts only the CA supplied and presents a client certificate, both PEM encoded.
+fn certificate_connector(ca: &str, certificate: &str, key: &str) -> MakeRustlsConnect {
+  let config = client_config_builder(ca)
+    .with_client_auth_cert(
+      vec![CertificateDer::from_pem_slice(certificate.as_bytes()).unwrap()],
+      PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+    )
+    .unwrap();
+  MakeRustlsConnect::new(config)
+}
+
+/// Client configuration that trusts only the CA supplied, missing how the client authenticates.
+fn client_config_builder(ca: &str) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+  let mut roots = RootCertStore::empty();
+  roots.add(CertificateDer::from_pem_slice(ca.as_bytes()).unwrap()).unwrap();
+  ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+}
+
+/// Client side TLS that supports `tls-server-end-point` channel binding, which tokio-postgres-rustls fails to report.
+#[derive(Clone)]
+struct BindingConnector(TlsConnector, String);
+
+/// Connection made by [`BindingConnector`].
+struct BindingStream<S>(client::TlsStream<S>);
+
+impl BindingConnector {
+  fn new(ca: &str) -> Self {
+    BindingConnector(TlsConnector::from(Arc::new(client_config(ca))), String::new())
+  }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> MakeTlsConnect<S> for BindingConnector {
+  type Stream = BindingStream<S>;
+  type TlsConnect = BindingConnector;
+  type Error = io::Error;
+
+  fn make_tls_connect(&mut self, domain: &str) -> io::Result<Self::TlsConnect> {
+    Ok(BindingConnector(self.0.clone(), domain.to_owned()))
+  }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> TlsConnect<S> for BindingConnector {
+  type Stream = BindingStream<S>;
+  type Error = io::Error;
+  type Future = Pin<Box<dyn Future<Output = io::Result<BindingStream<S>>> + Send>>;
+
+  fn connect(self, stream: S) -> Self::Future {
+    Box::pin(async move {
+      let domain = ServerName::try_from(self.1).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+      Ok(BindingStream(self.0.connect(domain, stream).await?))
+    })
+  }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream for BindingStream<S> {
+  /// Hashes the server certificate with SHA-256, the hash function of the ECDSA signature of generated certificates.
+  fn channel_binding(&self) -> ClientChannelBinding {
+    match self.0.get_ref().1.peer_certificates() {
+      Some([certificate, ..]) => ClientChannelBinding::tls_server_end_point(Sha256::digest(certificate).to_vec()),
+      _ => ClientChannelBinding::none(),
+    }
+  }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for BindingStream<S> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_read(cx, buf)
+  }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for BindingStream<S> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.0).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_shutdown(cx)
+  }
+}
+
+/// Client configuration skipping SSLRequest.
+fn direct_config(port: u16) -> Config {
+  let mut config = format!("host=localhost port={} user=postgres sslmode=require", port)
+    .parse::<Config>()
+    .unwrap();
+  let _ = config.ssl_negotiation(SslNegotiation::Direct);
+  config
+}
+
+#[tokio::test]
+async fn test_tls_required() {
+  let (ca, certificate, key) = certificates();
+  let configuration = ephemeral_configuration().with_tls(certificate.as_bytes(), key.as_bytes()).unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+
+  // The client verifies the certificate chain and that the certificate was issued for the host it connects to.
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=require", server.address().port()),
+    connector(&ca),
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  // A client that does not trust the CA refuses the server.
+  let (other_ca, _, _) = certificates();
+  let result = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=require", server.address().port()),
+    connector(&other_ca),
+  )
+  .await;
+  assert!(result.is_err());
+
+  // Clients can still connect without TLS.
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=disable", server.address().port()),
+    NoTls,
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_tls_refused() {
+  let (ca, _, _) = certificates();
+  let server = Postmaster::new(ephemeral_configuration()).spawn().await.unwrap();
+
+  // The server answers 'N' so a client that requires TLS gives up while one that prefers it carries on unencrypted.
+  let result = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=require", server.address().port()),
+    connector(&ca),
+  )
+  .await;
+  assert!(result.is_err());
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=prefer", server.address().port()),
+    connector(&ca),
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_generated_tls() {
+  let configuration = ephemeral_configuration().with_generated_tls().unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+
+  // The certificate is valid for both the name and the address the server listens in, which rustls checks as verify-full would.
+  for host in ["localhost", "127.0.0.1"] {
+    let (client, connection) = tokio_postgres::connect(
+      &format!("host={} port={} user=postgres sslmode=require", host, server.address().port()),
+      connector(&ca),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(connection);
+    assert!(client.batch_execute("SELECT 1").await.is_ok());
+  }
+}
+
+#[test]
+fn test_generated_tls_background() {
+  let configuration = ephemeral_configuration().with_generated_tls().unwrap();
+  let server = Postmaster::new(configuration).start_in_background().unwrap();
+  assert!(server.ca_certificate().unwrap().starts_with("-----BEGIN CERTIFICATE-----"));
+
+  // Certificates supplied by the caller have no CA to expose.
+  let (_, certificate, key) = certificates();
+  let configuration = ephemeral_configuration().with_tls(certificate.as_bytes(), key.as_bytes()).unwrap();
+  let server = Postmaster::new(configuration).start_in_background().unwrap();
+  assert!(server.ca_certificate().is_none());
+}
+
+#[tokio::test]
+async fn test_direct_tls() {
+  let configuration = ephemeral_configuration().with_generated_tls().unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+
+  let (client, connection) = direct_config(server.address().port()).connect(direct_connector(&ca)).await.unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  // Clients that do not negotiate the protocol through ALPN are refused.
+  assert!(direct_config(server.address().port()).connect(connector(&ca)).await.is_err());
+
+  // SSLRequest still works, with or without ALPN.
+  for connector in [connector(&ca), direct_connector(&ca)] {
+    let (client, connection) = tokio_postgres::connect(
+      &format!("host=localhost port={} user=postgres sslmode=require", server.address().port()),
+      connector,
+    )
+    .await
+    .unwrap();
+    tokio::spawn(connection);
+    assert!(client.batch_execute("SELECT 1").await.is_ok());
+  }
+}
+
+#[tokio::test]
+async fn test_direct_tls_forbidden() {
+  let configuration = ephemeral_configuration().with_generated_tls().unwrap().with_direct_tls(false);
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  assert!(direct_config(server.address().port()).connect(direct_connector(&ca)).await.is_err());
+
+  // Falling back to SSLRequest succeeds.
+  let (client, connection) = tokio_postgres::connect(
+    &format!("host=localhost port={} user=postgres sslmode=require", server.address().port()),
+    direct_connector(&ca),
+  )
+  .await
+  .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+}
+
+/// Configuration with generated certificates authenticating clients with SCRAM-SHA-256 and the password "secret".
+fn scram_configuration(channel_binding: ChannelBinding) -> Configuration {
+  Configuration::new(
+    "postgres".to_owned(),
+    Some("secret".to_owned()),
+    None,
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+    AuthenticationType::AuthenticationSASL,
+  )
+  .with_generated_tls()
+  .unwrap()
+  .with_channel_binding(channel_binding)
+}
+
+#[tokio::test]
+async fn test_channel_binding() {
+  let server = Postmaster::new(scram_configuration(ChannelBinding::Offer)).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  for channel_binding in ["require", "prefer", "disable"] {
+    let (client, connection) = tokio_postgres::connect(
+      &format!(
+        "host=localhost port={} user=postgres password=secret sslmode=require channel_binding={}",
+        server.address().port(),
+        channel_binding
+      ),
+      BindingConnector::new(&ca),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(connection);
+    assert!(client.batch_execute("SELECT 1").await.is_ok());
+  }
+
+  // Without TLS there is nothing to bind to.
+  let result = tokio_postgres::connect(
+    &format!(
+      "host=localhost port={} user=postgres password=secret sslmode=disable channel_binding=require",
+      server.address().port()
+    ),
+    NoTls,
+  )
+  .await;
+  assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_channel_binding_downgrade() {
+  // A client requiring channel binding refuses a server that does not offer SCRAM-SHA-256-PLUS.
+  let server = Postmaster::new(scram_configuration(ChannelBinding::Omit)).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  let url = |channel_binding: &str| {
+    format!(
+      "host=localhost port={} user=postgres password=secret sslmode=require channel_binding={}",
+      server.address().port(),
+      channel_binding
+    )
+  };
+  let result = tokio_postgres::connect(&url("require"), BindingConnector::new(&ca)).await;
+  assert!(matches!(result, Err(error) if error.code().is_none()));
+  let (client, connection) = tokio_postgres::connect(&url("prefer"), BindingConnector::new(&ca)).await.unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  // A server signing binding data other than the one the client sent is refused by the client itself.
+  let server = Postmaster::new(scram_configuration(ChannelBinding::Wrong)).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  let result = tokio_postgres::connect(
+    &format!(
+      "host=localhost port={} user=postgres password=secret sslmode=require channel_binding=require",
+      server.address().port()
+    ),
+    BindingConnector::new(&ca),
+  )
+  .await;
+  assert!(matches!(result, Err(error) if error.code().is_none()));
+}
+
+/// Creates a CA issuing client certificates.
+fn client_ca() -> (Certificate, KeyPair) {
+  let key = KeyPair::generate().unwrap();
+  let mut params = CertificateParams::new(Vec::new()).unwrap();
+  params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+  (params.self_signed(&key).unwrap(), key)
+}
+
+/// Issues a client certificate with the common name supplied, returning it and its key as PEM.
+fn client_certificate(ca: &(Certificate, KeyPair), common_name: &str) -> (String, String) {
+  let key = KeyPair::generate().unwrap();
+  let mut params = CertificateParams::new(Vec::new()).unwrap();
+  params.distinguished_name = DistinguishedName::new();
+  params.distinguished_name.push(DnType::CommonName, common_name);
+  let certificate = params.signed_by(&key, &ca.0, &ca.1).unwrap();
+  (certificate.pem(), key.serialize_pem())
+}
+
+#[tokio::test]
+async fn test_certificate_authentication() {
+  let client_issuer = client_ca();
+  // The client CA applies to TLS configured afterwards.
+  let configuration = ephemeral_configuration()
+    .with_client_ca(client_issuer.0.pem().as_bytes())
+    .unwrap()
+    .with_generated_tls()
+    .unwrap()
+    .with_role(Role::new("alice".to_owned()))
+    .with_role(Role::new("bob".to_owned()))
+    .with_hba(
+      "hostssl mapped all 127.0.0.1/32 cert map=users
+hostssl all    all 127.0.0.1/32 cert",
+    )
+    .unwrap()
+    .with_ident("users /^(.*)@example\\.com$ \\1")
+    .unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  let url = |user: &str, dbname: &str| {
+    format!(
+      "host=localhost port={} user={} dbname={} sslmode=require",
+      server.address().port(),
+      user,
+      dbname
+    )
+  };
+
+  // Without a map the common name must be the user.
+  let (certificate, key) = client_certificate(&client_issuer, "alice");
+  let (client, connection) = tokio_postgres::connect(&url("alice", "postgres"), certificate_connector(&ca, &certificate, &key))
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+  let result = tokio_postgres::connect(&url("bob", "postgres"), certificate_connector(&ca, &certificate, &key)).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)));
+
+  // The map turns the common name into the user through the capture group of the regular expression.
+  let (certificate, key) = client_certificate(&client_issuer, "bob@example.com");
+  let (client, connection) = tokio_postgres::connect(&url("bob", "mapped"), certificate_connector(&ca, &certificate, &key))
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+  let result = tokio_postgres::connect(&url("alice", "mapped"), certificate_connector(&ca, &certificate, &key)).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)));
+
+  // Clients that send no certificate complete the handshake but fail authentication.
+  let result = tokio_postgres::connect(&url("alice", "postgres"), connector(&ca)).await;
+  assert!(matches!(result, Err(error) if error.code() == Some(&SqlState::INVALID_AUTHORIZATION_SPECIFICATION)));
+
+  // Certificates issued by another CA are refused during the handshake.
+  let (certificate, key) = client_certificate(&client_ca(), "alice");
+  let result = tokio_postgres::connect(&url("alice", "postgres"), certificate_connector(&ca, &certificate, &key)).await;
+  assert!(matches!(result, Err(error) if error.code().is_none()));
+
+  // The client CA applies to TLS configured before too.
+  let configuration = ephemeral_configuration()
+    .with_generated_tls()
+    .unwrap()
+    .with_client_ca(client_issuer.0.pem().as_bytes())
+    .unwrap()
+    .with_hba("hostssl all all 127.0.0.1/32 cert")
+    .unwrap();
+  let server = Postmaster::new(configuration).spawn().await.unwrap();
+  let ca = server.ca_certificate().unwrap().to_owned();
+  let (certificate, key) = client_certificate(&client_issuer, "postgres");
+  let url = format!("host=localhost port={} user=postgres sslmode=require", server.address().port());
+  let (client, connection) = tokio_postgres::connect(&url, certificate_connector(&ca, &certificate, &key))
+    .await
+    .unwrap();
+  tokio::spawn(connection);
+  assert!(client.batch_execute("SELECT 1").await.is_ok());
+
+  assert!(ephemeral_configuration().with_client_ca(b"garbage").is_err());
+}